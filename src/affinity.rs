@@ -0,0 +1,51 @@
+//! Pinning every command to a CPU of its own, round-robin (see
+//! [`RunOptions::cpu_affinity`]).
+//!
+//! [`RunOptions::cpu_affinity`]: crate::RunOptions::cpu_affinity
+
+/// The CPUs ptsd itself may run on, in order, which the commands are
+/// spread over.
+#[cfg(target_os = "linux")]
+pub(crate) fn allowed_cpus() -> std::io::Result<Vec<usize>> {
+    // SAFETY: `cpu_set_t` is a plain bit set, for which all zeroes is valid.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `set` is as large as the size it's passed with.
+    if unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        // SAFETY: `cpu` is within the set.
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn allowed_cpus() -> std::io::Result<Vec<usize>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Make `command` pin itself to `cpu` before it starts.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_to_cpu(command: &mut tokio::process::Command, cpu: usize) {
+    // Built before forking, so the child only makes the syscall.
+    // SAFETY: as in `allowed_cpus`; `cpu` is one of its CPUs, so it fits in
+    // the set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    // SAFETY: `sched_setaffinity` is a plain syscall, and only affects the
+    // child.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_to_cpu(_command: &mut tokio::process::Command, _cpu: usize) {}
@@ -0,0 +1,47 @@
+//! One-shot signals that abort or wind down a run, raised from wherever the
+//! trouble is noticed and awaited by the spawn loop.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+#[derive(Default)]
+pub(crate) struct Alarm {
+    raised: AtomicBool,
+    notify: Notify,
+}
+
+impl Alarm {
+    pub fn raise(&self) {
+        self.raised.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the alarm is raised.
+    pub async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_raised() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Wait for `alarm` to be raised, forever if there is none.
+pub(crate) async fn wait_for(alarm: &Option<Arc<Alarm>>) {
+    match alarm {
+        Some(alarm) => alarm.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether `alarm` exists and was raised.
+pub(crate) fn is_raised(alarm: &Option<Arc<Alarm>>) -> bool {
+    alarm.as_ref().is_some_and(|alarm| alarm.is_raised())
+}
@@ -0,0 +1,96 @@
+//! A single log interleaving the output of every task in the order it was
+//! read (see [`RunOptions::combined_log`]).
+//!
+//! [`RunOptions::combined_log`]: crate::RunOptions::combined_log
+
+use crate::output::{LineSplitter, LogSink};
+use crate::Stream;
+use std::fs::File;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The file all tasks write their lines to, each prefixed with when it was
+/// read and the index of the task.
+pub(crate) struct CombinedLog {
+    file: Mutex<File>,
+}
+
+impl CombinedLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+
+    fn write_line(&self, index: usize, stream: Stream, line: &[u8]) -> io::Result<()> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let stream = match stream {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+        let mut record = format!(
+            "{} #{index} {stream}: ",
+            humantime::format_rfc3339_millis(SystemTime::now())
+        )
+        .into_bytes();
+        record.extend_from_slice(line);
+        record.push(b'\n');
+        // A single write per line, so that lines of different tasks don't
+        // get mixed up.
+        self.file.lock().unwrap().write_all(&record)
+    }
+}
+
+/// Copies every line written to the wrapped sink to the combined log, long
+/// ones handled as [`LineSplitter`] does.
+pub(crate) struct CombinedLogTee<W> {
+    inner: W,
+    index: usize,
+    stream: Stream,
+    log: Arc<CombinedLog>,
+    lines: LineSplitter,
+}
+
+impl<W> CombinedLogTee<W> {
+    pub fn new(
+        inner: W,
+        index: usize,
+        stream: Stream,
+        log: Arc<CombinedLog>,
+        max_line_length: Option<NonZeroUsize>,
+    ) -> Self {
+        Self {
+            inner,
+            index,
+            stream,
+            log,
+            lines: LineSplitter::new(max_line_length),
+        }
+    }
+}
+
+impl<W: Write> Write for CombinedLogTee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let (log, index, stream) = (&self.log, self.index, self.stream);
+        self.lines
+            .write(&buf[..written], |line| log.write_line(index, stream, line))?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl LogSink for CombinedLogTee<Box<dyn LogSink>> {
+    fn finish(mut self: Box<Self>, success: bool) -> io::Result<()> {
+        if let Some(partial) = self.lines.finish() {
+            self.log.write_line(self.index, self.stream, &partial)?;
+        }
+        self.inner.finish(success)
+    }
+}
@@ -0,0 +1,65 @@
+//! Substituting environment variables into commands before they're run, the
+//! way `envsubst` does.
+
+use crate::SetupError;
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Replace every `$NAME` and `${NAME}` in `command` with the value `lookup`
+/// gives for `NAME`.
+///
+/// Anything else starting with a `$`, like `$1` or `$(...)`, is left alone.
+/// Variables `lookup` knows nothing about are an error, unless
+/// `allow_undefined` is set, in which case they expand to nothing.
+pub fn expand_env(
+    command: &str,
+    allow_undefined: bool,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, SetupError> {
+    let mut expanded = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, len) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end)
+                    if braced[..end].starts_with(is_name_start)
+                        && braced[..end].chars().all(is_name_char) =>
+                {
+                    (&braced[..end], end + 2)
+                }
+                _ => ("", 0),
+            }
+        } else if after.starts_with(is_name_start) {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], end)
+        } else {
+            ("", 0)
+        };
+
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            match lookup(name) {
+                Some(value) => expanded.push_str(&value),
+                None if allow_undefined => {}
+                None => {
+                    return Err(SetupError::UndefinedVariable {
+                        command: command.to_string(),
+                        name: name.to_string(),
+                    })
+                }
+            }
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
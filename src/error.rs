@@ -0,0 +1,155 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Exit code used when every task succeeded.
+pub const EXIT_SUCCESS: u8 = 0;
+/// Exit code used when one or more tasks failed.
+pub const EXIT_TASKS_FAILED: u8 = 1;
+/// Exit code used for invalid command-line arguments, as clap does.
+pub const EXIT_USAGE: u8 = 2;
+/// Exit code used when a command file could not be read.
+pub const EXIT_COMMAND_FILE_FAILED: u8 = 3;
+/// Exit code used when the log directory or the combined log could not be
+/// created.
+pub const EXIT_LOG_DIR_FAILED: u8 = 4;
+/// Exit code used when some commands failed `--prelaunch-check`.
+pub const EXIT_PRELAUNCH_CHECK_FAILED: u8 = 5;
+/// Exit code used when a hook failed under `--strict-hooks`.
+pub const EXIT_HOOK_FAILED: u8 = 6;
+/// Exit code used when the status endpoint could not listen.
+pub const EXIT_SERVE_FAILED: u8 = 7;
+/// Exit code used when the disk filled up under `--abort-on-disk-full` (see
+/// [`RunReport::disk_full`](crate::RunReport::disk_full)).
+pub const EXIT_DISK_FULL: u8 = 8;
+/// Exit code used when a command used an unset variable.
+pub const EXIT_UNDEFINED_VARIABLE: u8 = 9;
+/// Exit code used when the shell is missing under `--require-shell-exists`.
+pub const EXIT_MISSING_SHELL: u8 = 10;
+/// Exit code used when the summary couldn't be posted under
+/// `--strict-webhook`.
+pub const EXIT_WEBHOOK_FAILED: u8 = 11;
+/// Exit code used when the `--precheck` probe failed.
+pub const EXIT_PROBE_FAILED: u8 = 12;
+/// Exit code used when the run was interrupted, following the shell's
+/// convention for SIGINT.
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+/// Problems that prevent ptsd from running the batch at all, as opposed to
+/// the failures of individual tasks.
+#[derive(Debug)]
+pub enum SetupError {
+    /// A command file could not be read.
+    CommandFile { path: PathBuf, source: io::Error },
+    /// The log directory could not be created.
+    LogDir {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// Some commands failed `--prelaunch-check`; holds each failed command's
+    /// index, text and the shell's complaint.
+    PrelaunchCheck(Vec<(usize, String, String)>),
+    /// The combined log (see `RunOptions::combined_log`) could not be
+    /// created.
+    CombinedLog { path: PathBuf, source: io::Error },
+    /// The readiness probe run before any task (see
+    /// `RunOptions::precheck`) failed, with this exit code, if it exited at
+    /// all, and output.
+    ProbeFailed {
+        command: String,
+        exit_code: Option<i32>,
+        output: String,
+    },
+    /// The status endpoint could not listen on the requested address.
+    Serve { addr: SocketAddr, source: io::Error },
+    /// A command refers to an environment variable that isn't set (see
+    /// [`expand_env`](crate::expand_env)).
+    UndefinedVariable { command: String, name: String },
+    /// The shell isn't an executable file (see
+    /// `RunOptions::require_shell_exists`).
+    MissingShell { shell: String, problem: String },
+}
+
+impl SetupError {
+    /// The process exit code that reports this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            SetupError::CommandFile { .. } => EXIT_COMMAND_FILE_FAILED,
+            SetupError::LogDir { .. } | SetupError::CombinedLog { .. } => EXIT_LOG_DIR_FAILED,
+            SetupError::PrelaunchCheck(_) => EXIT_PRELAUNCH_CHECK_FAILED,
+            SetupError::Serve { .. } => EXIT_SERVE_FAILED,
+            SetupError::UndefinedVariable { .. } => EXIT_UNDEFINED_VARIABLE,
+            SetupError::MissingShell { .. } => EXIT_MISSING_SHELL,
+            SetupError::ProbeFailed { .. } => EXIT_PROBE_FAILED,
+        }
+    }
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetupError::CommandFile { path, source } => {
+                write!(f, "Failed reading extra commands from {path:?}: {source}")
+            }
+            SetupError::LogDir {
+                path: Some(path),
+                source,
+            } => write!(f, "Failed creating the log directory {path:?}: {source}"),
+            SetupError::LogDir { path: None, source } => {
+                write!(f, "Failed creating a temporary log directory: {source}")
+            }
+            SetupError::CombinedLog { path, source } => {
+                write!(f, "Failed creating the combined log {path:?}: {source}")
+            }
+            SetupError::PrelaunchCheck(problems) => {
+                write!(f, "The following commands failed the pre-launch check:")?;
+                for (i, cmd, problem) in problems {
+                    write!(f, "\n  #{i} {cmd:?}: {problem}")?;
+                }
+                Ok(())
+            }
+            SetupError::ProbeFailed {
+                command,
+                exit_code,
+                output,
+            } => {
+                match exit_code {
+                    Some(code) => write!(
+                        f,
+                        "The precheck {command:?} failed with exit code {code}, no tasks were run"
+                    )?,
+                    None => write!(f, "The precheck {command:?} failed, no tasks were run")?,
+                }
+                if !output.is_empty() {
+                    write!(f, ":\n{output}")?;
+                }
+                Ok(())
+            }
+            SetupError::Serve { addr, source } => {
+                write!(f, "Failed serving the run status on {addr}: {source}")
+            }
+            SetupError::UndefinedVariable { command, name } => {
+                write!(f, "The variable {name:?} used by {command:?} is not set")
+            }
+            SetupError::MissingShell { shell, problem } => {
+                write!(f, "Can't run commands with the shell {shell:?}: {problem}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SetupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SetupError::CommandFile { source, .. }
+            | SetupError::LogDir { source, .. }
+            | SetupError::CombinedLog { source, .. }
+            | SetupError::Serve { source, .. } => Some(source),
+            SetupError::PrelaunchCheck(_)
+            | SetupError::UndefinedVariable { .. }
+            | SetupError::MissingShell { .. }
+            | SetupError::ProbeFailed { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! A stream of what happens during a run, for embedders that want to report
+//! on it their own way (see [`RunOptions::events`]).
+//!
+//! [`RunOptions::events`]: crate::RunOptions::events
+
+use crate::output::{LineSplitter, LogSink};
+use crate::{Stream, TaskReport};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Something that happened during a run.
+#[derive(Debug, Clone)]
+pub enum TaskEvent {
+    /// The task at `index` is about to be spawned.
+    Started { index: usize, command: String },
+    /// The task at `index` printed a line, without its line terminator. A
+    /// trailing line the task didn't terminate is sent once it exits. Lines
+    /// longer than [`RunOptions::max_line_length`] are cut short, or without
+    /// one, sent in pieces of 64 KiB.
+    ///
+    /// [`RunOptions::max_line_length`]: crate::RunOptions::max_line_length
+    OutputLine {
+        index: usize,
+        stream: Stream,
+        line: String,
+    },
+    /// A task finished, was skipped or was never started.
+    Finished(TaskReport),
+    /// Every task is done. This is the last event of the run.
+    RunComplete { success: bool, interrupted: bool },
+}
+
+/// Send `event`, if anyone asked for events. Events are dropped once the
+/// receiving side goes away.
+pub(crate) fn send(events: &Option<UnboundedSender<TaskEvent>>, event: TaskEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+/// Splits the output written to the wrapped sink into lines, sending each of
+/// them as an event.
+pub(crate) struct LineEvents<W> {
+    inner: W,
+    sender: LineSender,
+    lines: LineSplitter,
+}
+
+/// Where the lines of one stream of a task are sent.
+struct LineSender {
+    index: usize,
+    stream: Stream,
+    events: UnboundedSender<TaskEvent>,
+}
+
+impl LineSender {
+    fn send(&self, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let _ = self.events.send(TaskEvent::OutputLine {
+            index: self.index,
+            stream: self.stream,
+            line: String::from_utf8_lossy(line).into_owned(),
+        });
+    }
+}
+
+impl<W> LineEvents<W> {
+    pub fn new(
+        inner: W,
+        index: usize,
+        stream: Stream,
+        events: UnboundedSender<TaskEvent>,
+        max_line_length: Option<NonZeroUsize>,
+    ) -> Self {
+        Self {
+            inner,
+            sender: LineSender {
+                index,
+                stream,
+                events,
+            },
+            lines: LineSplitter::new(max_line_length),
+        }
+    }
+}
+
+impl<W: Write> Write for LineEvents<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let sender = &self.sender;
+        self.lines.write(&buf[..written], |line| {
+            sender.send(line);
+            Ok(())
+        })?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl LogSink for LineEvents<Box<dyn LogSink>> {
+    fn finish(mut self: Box<Self>, success: bool) -> io::Result<()> {
+        if let Some(partial) = self.lines.finish() {
+            self.sender.send(&partial);
+        }
+        self.inner.finish(success)
+    }
+}
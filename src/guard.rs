@@ -0,0 +1,77 @@
+//! Tying the tokio tasks that supervise the commands to the run that spawned
+//! them.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::task::{JoinError, JoinHandle};
+
+/// A spawned tokio task that is aborted once its handle is dropped, unlike a
+/// plain [`JoinHandle`], which detaches it.
+///
+/// The commands are spawned with `kill_on_drop`, so aborting the task that
+/// supervises one kills it. Holding these guards rather than plain handles
+/// makes sure no command outlives the run, however the run ends: returning
+/// early on an error, or having its future dropped by the embedder.
+pub(crate) struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T: Send + 'static> AbortOnDrop<T> {
+    pub fn spawn(future: impl Future<Output = T> + Send + 'static) -> Self {
+        Self(tokio::spawn(future))
+    }
+}
+
+impl<T> AbortOnDrop<T> {
+    /// Abort the task without waiting for the handle to be dropped.
+    pub fn abort(&self) {
+        self.0.abort();
+    }
+}
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The process group of a command that was started in one of its own,
+/// which is killed once this is dropped, so that nothing the command started
+/// outlives it: killing the command itself leaves its children running, and
+/// holding on to its pipes.
+pub(crate) struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    /// The group led by `pid`, if the command has a group of its own.
+    pub fn new(pid: Option<u32>, own_group: bool) -> Self {
+        Self(pid.filter(|_| own_group))
+    }
+
+    /// Kill every process in the group.
+    #[cfg(unix)]
+    pub fn kill(&self) {
+        if let Some(pgid) = self.0 {
+            // SAFETY: `killpg` is a plain syscall. The group is gone already
+            // if it fails.
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn kill(&self) {}
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
@@ -0,0 +1,109 @@
+//! Replacing identical log files with hard links to a single copy, once the
+//! run is over (see [`RunOptions::dedupe_logs`]).
+//!
+//! [`RunOptions::dedupe_logs`]: crate::RunOptions::dedupe_logs
+
+use crate::manifest::sha256_file;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// How much [`link_identical_logs`] saved.
+#[derive(Debug, Default)]
+pub(crate) struct LinkedLogs {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Whether the two files hold the same bytes.
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let (mut a_buf, mut b_buf) = ([0; 8192], [0; 8192]);
+    loop {
+        let read = a.read(&mut a_buf)?;
+        if read == 0 {
+            return Ok(b.read(&mut b_buf[..1])? == 0);
+        }
+        b.read_exact(&mut b_buf[..read])?;
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Replace `duplicate` with a hard link to `original`, without there being
+/// a moment where `duplicate` doesn't exist.
+fn replace_with_link(original: &Path, duplicate: &Path) -> io::Result<()> {
+    let mut link = duplicate.as_os_str().to_owned();
+    link.push(".link");
+    let link = PathBuf::from(link);
+    std::fs::hard_link(original, &link)?;
+    std::fs::rename(&link, duplicate).inspect_err(|_| {
+        let _ = std::fs::remove_file(&link);
+    })
+}
+
+/// Hard-link every non-empty file of `log_files` whose contents are the
+/// same as those of an earlier one (in path order) to it.
+///
+/// Files are only linked once their contents were compared byte for byte,
+/// not just their hashes. Where hard links can't be made (e.g. the
+/// filesystem doesn't support them, or the files are on different ones),
+/// the files are left as they are.
+pub(crate) fn link_identical_logs(log_files: &HashSet<PathBuf>) -> io::Result<LinkedLogs> {
+    let mut log_files: Vec<_> = log_files.iter().collect();
+    log_files.sort();
+
+    let mut originals: HashMap<(u64, String), Vec<(&PathBuf, std::fs::Metadata)>> = HashMap::new();
+    let mut linked = LinkedLogs::default();
+    for path in log_files {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // Such as the deferred logs of tasks that succeeded.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if !metadata.is_file() || metadata.len() == 0 {
+            continue;
+        }
+        let key = (metadata.len(), sha256_file(path)?);
+        let candidates = originals.entry(key).or_default();
+        // Nothing to save on a file that already is one of them.
+        if candidates
+            .iter()
+            .any(|(_, candidate_metadata)| same_file(candidate_metadata, &metadata))
+        {
+            continue;
+        }
+        let mut original = None;
+        for (candidate, _) in candidates.iter() {
+            if same_contents(candidate, path)? {
+                original = Some(*candidate);
+                break;
+            }
+        }
+        match original {
+            Some(original) => {
+                if replace_with_link(original, path).is_ok() {
+                    linked.files += 1;
+                    linked.bytes += metadata.len();
+                }
+            }
+            None => candidates.push((path, metadata)),
+        }
+    }
+    Ok(linked)
+}
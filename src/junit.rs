@@ -0,0 +1,130 @@
+//! Writing the results of a run as a JUnit XML report, for CI systems that
+//! show test results (see [`RunOptions::junit_report`]).
+//!
+//! Every task is a test case of a single `ptsd` test suite. Failed tasks
+//! carry the end of their stderr log, if they have one.
+//!
+//! [`RunOptions::junit_report`]: crate::RunOptions::junit_report
+
+use crate::{RunReport, TaskOutcome};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// How much of the end of a failed task's stderr goes in its test case.
+const STDERR_TAIL_BYTES: u64 = 4096;
+
+/// Escape `text` for use in XML attributes and text, dropping the control
+/// characters XML 1.0 doesn't allow at all.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The last [`STDERR_TAIL_BYTES`] of the log at `path`, starting at a line.
+fn stderr_tail(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(STDERR_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    if start > 0 {
+        if let Some(newline) = tail.iter().position(|&byte| byte == b'\n') {
+            tail.drain(..=newline);
+        }
+    }
+    Ok(String::from_utf8_lossy(&tail).into_owned())
+}
+
+/// Write `report` to `path` as JUnit XML, with the log files of each task
+/// by index.
+pub(crate) fn write_junit(
+    path: &Path,
+    report: &RunReport,
+    log_files: &HashMap<usize, Vec<PathBuf>>,
+) -> io::Result<()> {
+    let (mut failures, mut errors, mut skipped) = (0, 0, 0);
+    for task in &report.tasks {
+        match task.outcome {
+            TaskOutcome::Succeeded => {}
+            TaskOutcome::Duplicate { .. } | TaskOutcome::PreconditionUnmet => skipped += 1,
+            TaskOutcome::SpawnFailed(_) | TaskOutcome::InternalError(_) => errors += 1,
+            _ => failures += 1,
+        }
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuite name="ptsd" tests="{}" failures="{failures}" errors="{errors}" skipped="{skipped}" time="{:.3}">"#,
+        report.tasks.len(),
+        report.duration.as_secs_f64()
+    )?;
+    for task in &report.tasks {
+        write!(
+            out,
+            r#"  <testcase name="{}" classname="ptsd" time="{:.3}""#,
+            escape(&format!("{}: {}", task.index, task.command)),
+            task.duration.as_secs_f64()
+        )?;
+        let (element, message) = match &task.outcome {
+            TaskOutcome::Succeeded => {
+                writeln!(out, "/>")?;
+                continue;
+            }
+            TaskOutcome::Duplicate { of } => ("skipped", format!("duplicate of task {of}")),
+            TaskOutcome::PreconditionUnmet => ("skipped", "precondition not met".to_string()),
+            TaskOutcome::SpawnFailed(error) | TaskOutcome::InternalError(error) => {
+                ("error", error.clone())
+            }
+            TaskOutcome::Failed {
+                exit_code: Some(code),
+            } => ("failure", format!("exit code {code}")),
+            TaskOutcome::Failed { exit_code: None } => {
+                ("failure", "killed by a signal".to_string())
+            }
+            TaskOutcome::NoOutput => ("failure", "no output".to_string()),
+            TaskOutcome::WroteToStderr => ("failure", "wrote to stderr".to_string()),
+            TaskOutcome::FilterFailed { .. } => ("failure", "output filter failed".to_string()),
+            TaskOutcome::Interrupted => ("failure", "interrupted".to_string()),
+            TaskOutcome::TimedOut => ("failure", "timed out".to_string()),
+        };
+        writeln!(out, ">")?;
+        let stderr = log_files
+            .get(&task.index)
+            .and_then(|files| {
+                files
+                    .iter()
+                    .find(|path| path.extension().is_some_and(|ext| ext == "stderr"))
+            })
+            .filter(|_| element != "skipped")
+            .and_then(|path| stderr_tail(path).ok())
+            .filter(|tail| !tail.is_empty());
+        match stderr {
+            Some(stderr) => writeln!(
+                out,
+                r#"    <{element} message="{}">{}</{element}>"#,
+                escape(&message),
+                escape(&stderr)
+            )?,
+            None => writeln!(out, r#"    <{element} message="{}"/>"#, escape(&message))?,
+        }
+        writeln!(out, "  </testcase>")?;
+    }
+    writeln!(out, "</testsuite>")?;
+    out.flush()
+}
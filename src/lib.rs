@@ -0,0 +1,2127 @@
+//! Parallel Thing/Stuff Doer
+//!
+//! Run shell commands in parallel, writing each one's output to a log
+//! directory and reporting which of them failed.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), ptsd::SetupError> {
+//! let report = ptsd::run_commands(ptsd::RunOptions {
+//!     tasks: vec!["make -C a".into(), "make -C b".into()],
+//!     ..Default::default()
+//! })
+//! .await?;
+//! println!("Failed tasks: {:?}", report.failed_tasks());
+//! # Ok(())
+//! # }
+//! ```
+
+mod affinity;
+mod alarm;
+mod combined;
+mod envsubst;
+mod error;
+mod events;
+mod guard;
+mod hardlink;
+mod junit;
+mod link;
+mod manifest;
+mod matrix;
+mod messages;
+mod output;
+mod pause;
+mod pool;
+mod precheck;
+mod priority;
+mod progress;
+mod prune;
+#[cfg(unix)]
+mod pty;
+mod rlimit;
+mod serve;
+mod source;
+mod state;
+mod table;
+mod tap;
+mod transform;
+
+pub use envsubst::expand_env;
+pub use error::{
+    SetupError, EXIT_COMMAND_FILE_FAILED, EXIT_DISK_FULL, EXIT_HOOK_FAILED, EXIT_INTERRUPTED,
+    EXIT_LOG_DIR_FAILED, EXIT_MISSING_SHELL, EXIT_PRELAUNCH_CHECK_FAILED, EXIT_PROBE_FAILED,
+    EXIT_SERVE_FAILED, EXIT_SUCCESS, EXIT_TASKS_FAILED, EXIT_UNDEFINED_VARIABLE, EXIT_USAGE,
+    EXIT_WEBHOOK_FAILED,
+};
+pub use events::TaskEvent;
+pub use manifest::MANIFEST_FILE_NAME;
+pub use matrix::expand_matrix;
+pub use messages::MessageWriter;
+pub use output::{FileSink, LogSink, OutputSink, Stream};
+pub use priority::{IoClass, IoPriority};
+pub use progress::ProgressGlyphs;
+pub use rlimit::ResourceLimits;
+pub use source::TaskStream;
+pub use table::SummaryOrder;
+pub use transform::CommandTransform;
+
+use alarm::Alarm;
+use combined::CombinedLog;
+use guard::{AbortOnDrop, ProcessGroup};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use messages::Messages;
+use output::{OutputBudget, OutputFilter, OutputOptions, OutputPumps};
+use pause::FailurePause;
+use pool::{jitter, ramp_up, JobPool, MemoryGate, RateLimiter};
+use progress::{init_progress_styles, render_message, truncate_to_width, CompactProgress};
+use source::TaskSource;
+use state::{RunSnapshot, RunState, TaskState};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// How often the concurrency limit changes under [`RunOptions::jobs_min`].
+pub const JOBS_JITTER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the [`RunOptions::progress_to`] file is rewritten.
+pub const PROGRESS_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a supervised task waits before its command is restarted (see
+/// [`RunOptions::supervise`]).
+pub const RESTART_DELAY: Duration = Duration::from_millis(250);
+
+/// How often the progress spinners are redrawn by default.
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(80);
+
+/// How many times a second the progress bars are redrawn at most, by
+/// default.
+pub const DEFAULT_PROGRESS_FPS: u8 = 20;
+
+/// The shell used to run commands unless told otherwise.
+pub const DEFAULT_SHELL: &str = "/bin/bash";
+
+/// A single command to run, and how to run it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Task {
+    /// The command itself, run as `<shell> -c <command>`.
+    pub command: String,
+    /// The directory to run the command in. Defaults to ptsd's own working
+    /// directory.
+    pub cwd: Option<PathBuf>,
+    /// The interpreter to run this command with instead of
+    /// [`RunOptions::shell`]. It is invoked the same way, with `-c`.
+    pub shell: Option<String>,
+    /// A descriptive name for the task, added to the names of its log files
+    /// after its index.
+    pub name: Option<String>,
+    /// A shell command that has to succeed for the task to run at all;
+    /// otherwise the task is skipped. Preconditions are evaluated, in the
+    /// task's directory, before any task is started.
+    pub only_if: Option<String>,
+    /// Environment variables to set for the command, on top of the ones it
+    /// inherits (or is passed through, see [`RunOptions::env_passthrough`]).
+    pub env: BTreeMap<String, String>,
+    /// Kill the command if it's still running after this long. Whatever it
+    /// started is killed along with it if it has a process group of its own
+    /// (see [`RunOptions::graceful_interrupts`]).
+    pub timeout: Option<Duration>,
+}
+
+impl Task {
+    /// Split a leading `#!<interpreter>` annotation off the command, making
+    /// it the task's shell, e.g. `#!python3 print(1)`.
+    pub fn with_shell_annotation(mut self) -> Self {
+        if let Some(annotated) = self.command.strip_prefix("#!") {
+            let (shell, command) = annotated
+                .split_once(char::is_whitespace)
+                .unwrap_or((annotated, ""));
+            if !shell.is_empty() {
+                self.shell = Some(shell.to_string());
+                self.command = command.trim_start().to_string();
+            }
+        }
+        self
+    }
+}
+
+impl From<String> for Task {
+    fn from(command: String) -> Self {
+        Task {
+            command,
+            cwd: None,
+            shell: None,
+            name: None,
+            only_if: None,
+            env: BTreeMap::new(),
+            timeout: None,
+        }
+    }
+}
+
+impl From<&str> for Task {
+    fn from(command: &str) -> Self {
+        command.to_string().into()
+    }
+}
+
+/// Everything that controls how a batch of commands is run.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// The tasks to run, in order.
+    pub tasks: Vec<Task>,
+    /// More tasks to run after `tasks`, started as they arrive instead of
+    /// all being known up front. Their indices follow those of `tasks`.
+    /// They aren't covered by `prelaunch_check`, and `PTSD_TASK_TOTAL` is
+    /// only set if the stream knows its length.
+    pub streamed_tasks: Option<TaskStream>,
+    /// The shell to run the commands with.
+    pub shell: String,
+    /// Directory to place command outputs in. A temporary directory is
+    /// generated if left unspecified.
+    pub log_dir: Option<PathBuf>,
+    /// Where to write the logs of the remaining tasks once creating them in
+    /// `log_dir` fails because its file system is full, e.g. when `log_dir`
+    /// is a small tmpfs.
+    pub log_dir_fallback: Option<PathBuf>,
+    /// Discard the output of the tasks instead of logging it, without
+    /// creating a log directory at all. Output still goes to
+    /// [`RunOptions::output_sink`], if there is one.
+    pub no_log: bool,
+    /// The maximal number of commands to run in parallel. Never more than
+    /// the number of tasks, unless some are streamed in.
+    pub jobs: NonZeroUsize,
+    /// Don't draw progress bars.
+    pub disable_progress: bool,
+    /// Run this command with `shell` before anything else, and don't run any
+    /// of the tasks if it fails, e.g. to check that a service they all need
+    /// is up.
+    pub precheck: Option<String>,
+    /// Check that the working directories of the tasks exist, and
+    /// syntax-check every command with the shell, before running any of
+    /// them.
+    pub prelaunch_check: bool,
+    /// Make sure the shell is an executable file before running anything.
+    /// Commands with their own interpreter aren't checked.
+    pub require_shell_exists: bool,
+    /// Periodically report how many tasks are running, queued, done and
+    /// failed. The interval must not be zero.
+    pub stats_interval: Option<Duration>,
+    /// Only keep the output of failed tasks.
+    pub output_on_failure_only: bool,
+    /// When the first task fails, print where its logs are and hold the run
+    /// until Enter is pressed: the running tasks carry on, but no new ones
+    /// are started. Does nothing unless stdin is a terminal.
+    pub pause_on_failure: bool,
+    /// As soon as a task fails, print its logs along with ptsd's own
+    /// messages. Tasks whose output doesn't go to the log directory aren't
+    /// echoed.
+    pub echo_failures: bool,
+    /// Log every line of output as a JSON object with the task's index, the
+    /// stream, a timestamp and the line, into a single `<task>.jsonl` file
+    /// per task. Takes the place of `output_on_failure_only`.
+    pub log_json: bool,
+    /// With `output_on_failure_only`, the amount of output to hold in memory
+    /// per stream before spilling it to the log file.
+    pub output_buffer_limit: usize,
+    /// The most output to hold in memory for all tasks together, when it's
+    /// piped through ptsd. Output is only read from the tasks while there's
+    /// room for it, leaving them blocked on writing it until there is. With
+    /// `output_on_failure_only`, logs that would exceed it are spilled to
+    /// their files instead.
+    pub max_concurrent_output_bytes: Option<usize>,
+    /// Strip ANSI escape sequences from the output before writing the logs.
+    pub strip_ansi_logs: bool,
+    /// Start with a single job and gradually raise the limit up to `jobs`
+    /// over this period.
+    pub ramp: Option<Duration>,
+    /// Randomly vary the number of commands allowed to run in parallel
+    /// between this and `jobs`, every [`JOBS_JITTER_INTERVAL`]. The limit
+    /// never exceeds `jobs`. Ignored when ramping up.
+    pub jobs_min: Option<NonZeroUsize>,
+    /// Once the run is done, delete the oldest files in the log directory
+    /// until it takes at most this many bytes. Logs of the current run are
+    /// never deleted.
+    pub max_log_dir_size: Option<u64>,
+    /// Consider tasks that exited successfully but printed nothing as failed.
+    pub fail_on_empty_output: Option<EmptyOutputPolicy>,
+    /// Consider tasks that exited successfully but printed anything to
+    /// stderr as failed.
+    pub fail_on_stderr: bool,
+    /// Run every command under its own pseudo-terminal, so that it behaves as
+    /// if it was run interactively. Both output streams end up in the stdout
+    /// log. Only supported on Unix.
+    pub pty: bool,
+    /// Serve a JSON snapshot of the state of every task over HTTP on this
+    /// address while the run is in progress. The address served on, port
+    /// and all, is printed as a message.
+    pub serve: Option<SocketAddr>,
+    /// Write the commands' output to the log files byte-for-byte. This
+    /// overrides any option that would transform the output on its way to
+    /// the logs, such as `strip_ansi_logs`, `pty` and `log_json`.
+    pub binary_safe: bool,
+    /// Print the results of the tasks to stdout in the Test Anything Protocol
+    /// format as they finish.
+    pub tap: bool,
+    /// Once the run is done, write its results here as a JUnit XML report,
+    /// with every task as a test case. Failed tasks include the end of their
+    /// stderr log.
+    pub junit_report: Option<PathBuf>,
+    /// Warn about tasks that appear more than once in [`RunOptions::tasks`].
+    pub warn_duplicates: bool,
+    /// Skip tasks identical to an earlier one, implying
+    /// [`RunOptions::warn_duplicates`]. Skipped tasks keep their index.
+    pub dedup: bool,
+    /// Only run the tasks at these indices, leaving the others out of the
+    /// run and its report. The tasks keep their indices, and so the names of
+    /// their logs. The ranges may overlap.
+    pub select: Option<Vec<RangeInclusive<usize>>>,
+    /// Keep a JSON snapshot of the state of every task in this file,
+    /// rewritten every [`PROGRESS_FILE_INTERVAL`] and once more at the end.
+    pub progress_to: Option<PathBuf>,
+    /// Truncate the commands shown next to the progress bars to this many
+    /// terminal columns.
+    pub max_message_width: Option<usize>,
+    /// What to show next to the progress bar of each task instead of its
+    /// command, with `{index}`, `{name}` and `{cmd}` replaced by the task's
+    /// index, name and command. Tasks without a name use their command as
+    /// one. Applied before `max_message_width`.
+    pub message_template: Option<String>,
+    /// `fsync` the log files of every task before reporting it as done.
+    pub fsync_logs: bool,
+    /// Make every non-empty log file end with exactly one newline once its
+    /// task is done, so that the logs can be concatenated. Ignored with
+    /// `binary_safe`.
+    pub ensure_final_newline: bool,
+    /// Print the exact invocation of every task right before it is spawned.
+    pub verbose_spawn: bool,
+    /// How often to redraw the progress spinners. With `None` they are only
+    /// redrawn when a task changes state.
+    pub tick_interval: Option<Duration>,
+    /// Abort the whole run, killing the running tasks, as soon as writing a
+    /// log fails because the disk is full. Implies piping all output through
+    /// ptsd, so that such failures can be noticed at all.
+    pub abort_on_disk_full: bool,
+    /// With `disable_progress`, print a line whenever a task is started.
+    pub print_command_on_start: bool,
+    /// Send the output of the tasks to this sink instead of writing it to
+    /// log files in `log_dir`. `output_on_failure_only` is left to the sink,
+    /// which is told whether each task succeeded.
+    pub output_sink: Option<Arc<dyn OutputSink>>,
+    /// Show when each task was started next to its progress bar.
+    pub show_start_time: bool,
+    /// Run the tasks in an empty environment, except for these variables,
+    /// which are passed through from ptsd's own environment. Every task
+    /// gets `PTSD_TASK_INDEX`, `PTSD_TASK_NAME` (the name its logs are
+    /// written under) and `PTSD_TASK_TOTAL` (if the number of tasks is
+    /// known in advance) regardless.
+    pub env_passthrough: Option<Vec<String>>,
+    /// Where to draw the progress bars.
+    pub progress_target: ProgressTarget,
+    /// How many times a second to redraw the progress bars at most.
+    pub progress_fps: NonZeroU8,
+    /// The glyphs of the progress spinners.
+    pub progress_glyphs: ProgressGlyphs,
+    /// Also write every line of output of every task to this file as it is
+    /// read, prefixed with the time and the index of the task, to see how
+    /// the tasks interleave. Implies piping all output through ptsd.
+    pub combined_log: Option<PathBuf>,
+    /// Instead of a progress bar per task, draw a single one for the whole
+    /// run, and list the last few tasks to finish under it.
+    pub compact_progress: bool,
+    /// Draw a progress bar per task even when more tasks may run at once
+    /// than fit in the terminal. Otherwise, the progress is drawn as with
+    /// `compact_progress` once `jobs` (or the number of tasks, if there are
+    /// fewer) is over the terminal's rows less two, as the bars would keep
+    /// pushing each other off the screen.
+    pub full_progress: bool,
+    /// Handle Ctrl-C by no longer starting new tasks while letting the
+    /// running ones finish, and kill them on a second Ctrl-C.
+    ///
+    /// On Unix, tasks are moved to process groups of their own so that they
+    /// don't receive the terminal's interrupts themselves. The first
+    /// interrupt isn't passed on to them; the second kills their whole
+    /// process groups, so that nothing they started is left running.
+    pub graceful_interrupts: bool,
+    /// Once the log directory is created, point a symlink at this path to
+    /// it. Where symlinks aren't available, a file holding its path is
+    /// written instead.
+    pub latest_symlink: Option<PathBuf>,
+    /// Send an event whenever a task starts, prints a line or finishes (see
+    /// [`run_commands_with_events`]). Implies piping all output through
+    /// ptsd.
+    pub events: Option<UnboundedSender<TaskEvent>>,
+    /// Pipe the stdout and stderr of every task through this shell command
+    /// on their way to the logs. The task fails if the filter does. Implies
+    /// piping all output through ptsd.
+    pub output_filter: Option<String>,
+    /// Once the run is over, replace every log file of the run that holds
+    /// the same output as another one with a hard link to it, to save space
+    /// when many tasks print the same thing. Logs that can't be linked are
+    /// left as they are.
+    pub dedupe_logs: bool,
+    /// Once the run is over, write the SHA-256 of every log file of the run
+    /// to [`MANIFEST_FILE_NAME`] in the log directory.
+    pub checksum_logs: bool,
+    /// Collapse runs of identical consecutive lines in the logs into a
+    /// single `<line> (repeated N times)`. Implies piping all output through
+    /// ptsd.
+    pub dedupe_output: bool,
+    /// Cut the lines of output longer than this many bytes short, marking
+    /// them with how much was cut off, wherever output is handled line by
+    /// line: `dedupe_output`, `log_json`, `combined_log` and `events`. Lines
+    /// are otherwise split into pieces of 64 KiB. Doesn't affect plain logs.
+    pub max_line_length: Option<NonZeroUsize>,
+    /// Start at most this many tasks a second on average, on top of the
+    /// `jobs` limit. Up to a second's worth of tasks may start at once.
+    pub spawn_rate: Option<f64>,
+    /// Only start a task once the system has this many bytes of memory
+    /// available for it, on top of the `jobs` limit, counting the memory of
+    /// tasks started within the last second as taken. A task is always
+    /// started when none are running. Only supported on Linux; elsewhere a
+    /// warning is printed and tasks aren't held back.
+    pub reserve_memory_per_task: Option<u64>,
+    /// Write ptsd's own messages, like warnings and problems with
+    /// individual tasks, here instead of to stderr.
+    pub messages: Option<MessageWriter>,
+    /// Run the commands at this niceness, from -20 (the highest priority)
+    /// to 19. Only supported on Unix.
+    pub nice: Option<i32>,
+    /// Run the commands in this I/O scheduling class. Only supported on
+    /// Linux.
+    pub io_priority: Option<IoPriority>,
+    /// Pin every task to a single CPU, going round-robin over the CPUs ptsd
+    /// may run on by the tasks' indices, to keep the tasks from migrating
+    /// between cores, e.g. while benchmarking. Only supported on Linux;
+    /// elsewhere a warning is printed and the tasks aren't pinned.
+    pub cpu_affinity: bool,
+    /// Limit the CPU time, file sizes, memory and open files of each
+    /// command. Only supported on Unix.
+    pub resource_limits: ResourceLimits,
+    /// Restart the command of a task whenever it exits, successfully or
+    /// not, [`RESTART_DELAY`] later, the way a supervisor keeps services up.
+    /// A task is only done once it's out of restarts, or once the run is
+    /// interrupted. Restarted commands append to the logs of the task.
+    pub supervise: bool,
+    /// With `supervise`, restart each task at most this many times. With
+    /// `None` they're restarted for as long as the run goes on.
+    pub max_restarts: Option<u32>,
+    /// Restart the command of a task that looks like it was killed for
+    /// running out of memory (by `SIGKILL`, or exiting with 137) once,
+    /// taking up two job slots while it runs again, so that fewer tasks
+    /// compete with it for memory. The restart appends to the task's logs.
+    pub relaunch_on_oom: bool,
+}
+
+/// Which of ptsd's own output streams the progress bars are drawn to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressTarget {
+    Stdout,
+    Stderr,
+}
+
+/// What output a task must produce to not be considered silent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmptyOutputPolicy {
+    /// The task must write something to its stdout.
+    Stdout,
+    /// The task must write something to either its stdout or its stderr.
+    Any,
+}
+
+impl EmptyOutputPolicy {
+    fn is_silent(self, stdout_len: u64, stderr_len: u64) -> bool {
+        match self {
+            EmptyOutputPolicy::Stdout => stdout_len == 0,
+            EmptyOutputPolicy::Any => stdout_len == 0 && stderr_len == 0,
+        }
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            tasks: Vec::new(),
+            streamed_tasks: None,
+            shell: DEFAULT_SHELL.to_string(),
+            log_dir: None,
+            log_dir_fallback: None,
+            no_log: false,
+            jobs: std::thread::available_parallelism().unwrap_or(NonZeroUsize::new(12).unwrap()),
+            disable_progress: false,
+            prelaunch_check: false,
+            precheck: None,
+            require_shell_exists: false,
+            stats_interval: None,
+            output_on_failure_only: false,
+            pause_on_failure: false,
+            echo_failures: false,
+            log_json: false,
+            output_buffer_limit: 1 << 20,
+            max_concurrent_output_bytes: None,
+            strip_ansi_logs: false,
+            ramp: None,
+            jobs_min: None,
+            max_log_dir_size: None,
+            fail_on_empty_output: None,
+            fail_on_stderr: false,
+            pty: false,
+            serve: None,
+            binary_safe: false,
+            tap: false,
+            junit_report: None,
+            warn_duplicates: false,
+            dedup: false,
+            select: None,
+            progress_to: None,
+            max_message_width: None,
+            message_template: None,
+            fsync_logs: false,
+            ensure_final_newline: false,
+            verbose_spawn: false,
+            tick_interval: Some(DEFAULT_TICK_INTERVAL),
+            abort_on_disk_full: false,
+            print_command_on_start: false,
+            output_sink: None,
+            show_start_time: false,
+            env_passthrough: None,
+            progress_target: ProgressTarget::Stderr,
+            progress_fps: NonZeroU8::new(DEFAULT_PROGRESS_FPS).unwrap(),
+            progress_glyphs: ProgressGlyphs::default(),
+            compact_progress: false,
+            full_progress: false,
+            combined_log: None,
+            graceful_interrupts: false,
+            latest_symlink: None,
+            events: None,
+            output_filter: None,
+            checksum_logs: false,
+            dedupe_logs: false,
+            dedupe_output: false,
+            max_line_length: None,
+            spawn_rate: None,
+            reserve_memory_per_task: None,
+            messages: None,
+            nice: None,
+            io_priority: None,
+            cpu_affinity: false,
+            resource_limits: ResourceLimits::default(),
+            supervise: false,
+            max_restarts: None,
+            relaunch_on_oom: false,
+        }
+    }
+}
+
+/// How a single task ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskOutcome {
+    /// The command exited successfully.
+    Succeeded,
+    /// The command exited unsuccessfully. `exit_code` is `None` if the
+    /// command was terminated by a signal.
+    Failed { exit_code: Option<i32> },
+    /// The command exited successfully, but without printing anything.
+    NoOutput,
+    /// The command exited successfully, but printed to stderr (see
+    /// [`RunOptions::fail_on_stderr`]).
+    WroteToStderr,
+    /// The command could not be started at all.
+    SpawnFailed(String),
+    /// The task was not run, as it is identical to the earlier task at the
+    /// given index (see [`RunOptions::dedup`]).
+    Duplicate { of: usize },
+    /// ptsd itself failed while supervising the command.
+    InternalError(String),
+    /// The run was interrupted before the task started, or while it was
+    /// running, in which case it was killed.
+    Interrupted,
+    /// The command exited successfully, but the output filter (see
+    /// [`RunOptions::output_filter`]) of one of its streams didn't.
+    FilterFailed { exit_code: Option<i32> },
+    /// The task was not run, as its [`Task::only_if`] precondition failed.
+    PreconditionUnmet,
+    /// The command ran for longer than its [`Task::timeout`], and was
+    /// killed.
+    TimedOut,
+}
+
+impl TaskOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            TaskOutcome::Succeeded | TaskOutcome::Duplicate { .. } | TaskOutcome::PreconditionUnmet
+        )
+    }
+}
+
+/// The result of a single task.
+#[derive(Debug, Clone)]
+pub struct TaskReport {
+    /// The position of the task in [`RunOptions::tasks`].
+    pub index: usize,
+    pub command: String,
+    /// The [`Task::name`] of the task.
+    pub name: Option<String>,
+    pub outcome: TaskOutcome,
+    /// How long the task ran for.
+    pub duration: Duration,
+    /// The wall-clock time the task was started at.
+    pub started_at: SystemTime,
+    /// The wall-clock time the task finished at.
+    pub finished_at: SystemTime,
+    /// The process ID of the task's command, if it was started at all.
+    /// For a restarted task, that of the last one.
+    pub pid: Option<u32>,
+    /// How many times the command was restarted (see
+    /// [`RunOptions::supervise`]).
+    pub restarts: u32,
+    /// The signal that killed the task's command, if one did.
+    pub signal: Option<i32>,
+    /// The log files of the task, if it got as far as having any. With
+    /// [`RunOptions::output_on_failure_only`], those of a task that
+    /// succeeded are never written.
+    pub log_files: Vec<PathBuf>,
+}
+
+/// Tracks when a task started, to report when and how long it ran.
+#[derive(Clone, Copy)]
+struct TaskClock {
+    started: Instant,
+    started_at: SystemTime,
+}
+
+impl TaskClock {
+    fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    /// Report the task as finished just now.
+    fn report(&self, index: usize, task: Task, outcome: TaskOutcome) -> TaskReport {
+        TaskReport {
+            index,
+            command: task.command,
+            name: task.name,
+            outcome,
+            duration: self.started.elapsed(),
+            started_at: self.started_at,
+            finished_at: SystemTime::now(),
+            pid: None,
+            restarts: 0,
+            signal: None,
+            log_files: Vec::new(),
+        }
+    }
+}
+
+/// The result of a whole run.
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    /// Where the output of the commands was written, unless it wasn't
+    /// logged (see [`RunOptions::no_log`]).
+    pub log_dir: Option<PathBuf>,
+    /// If `log_dir` filled up (see [`RunOptions::log_dir_fallback`]), the
+    /// index of the first task whose logs were written to the fallback
+    /// directory instead, and the directory. The logs of all later tasks are
+    /// there too.
+    pub log_dir_fallback: Option<(usize, PathBuf)>,
+    /// The results of the individual tasks, ordered by index.
+    pub tasks: Vec<TaskReport>,
+    /// How long it took to run all of the tasks.
+    pub duration: Duration,
+    /// Whether the run was cut short by an interrupt (see
+    /// [`RunOptions::graceful_interrupts`]).
+    pub interrupted: bool,
+    /// Whether the run was aborted because the disk holding the logs filled
+    /// up (see [`RunOptions::abort_on_disk_full`]).
+    pub disk_full: bool,
+}
+
+impl RunReport {
+    /// The indices of the tasks that did not succeed.
+    pub fn failed_tasks(&self) -> Vec<usize> {
+        self.tasks
+            .iter()
+            .filter(|task| !task.outcome.is_success())
+            .map(|task| task.index)
+            .collect()
+    }
+
+    pub fn success(&self) -> bool {
+        self.tasks.iter().all(|task| task.outcome.is_success())
+    }
+
+    /// Write a human-readable summary of the run: whether it was
+    /// interrupted or aborted, and which tasks failed. At most `fail_summary_limit`
+    /// failed tasks are listed; with a limit of 0 only their number is.
+    pub fn write_summary(
+        &self,
+        out: &mut impl std::io::Write,
+        fail_summary_limit: Option<usize>,
+    ) -> std::io::Result<()> {
+        if self.disk_full {
+            writeln!(
+                out,
+                "The disk filled up, so the run was aborted; tasks that didn't get to finish \
+                 count as failed"
+            )?;
+        } else if self.interrupted {
+            writeln!(
+                out,
+                "The run was interrupted; tasks that didn't get to finish count as failed"
+            )?;
+        }
+        let failed_tasks = self.failed_tasks();
+        if !failed_tasks.is_empty() {
+            match fail_summary_limit {
+                Some(0) => writeln!(out, "{} tasks failed", failed_tasks.len())?,
+                Some(limit) if failed_tasks.len() > limit => writeln!(
+                    out,
+                    "The following tasks failed: {:?} ... and {} more",
+                    &failed_tasks[..limit],
+                    failed_tasks.len() - limit
+                )?,
+                _ => writeln!(out, "The following tasks failed: {:?}", failed_tasks)?,
+            }
+            match (&self.log_dir, &self.log_dir_fallback) {
+                (Some(log_dir), Some((first, fallback))) => writeln!(
+                    out,
+                    "You can view their output in {log_dir:?}, or in {fallback:?} from task {first} on"
+                )?,
+                (Some(log_dir), None) => writeln!(out, "You can view their output in {log_dir:?}")?,
+                (None, _) => {}
+            }
+        }
+        for task in self.tasks.iter().filter(|task| task.restarts > 0) {
+            let times = if task.restarts == 1 { "time" } else { "times" };
+            writeln!(
+                out,
+                "Task {} was restarted {} {times}",
+                task.index, task.restarts
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write a table with a row for every task: its index, command, how it
+    /// ended and how long it took, in the given order.
+    pub fn write_table(
+        &self,
+        out: &mut impl std::io::Write,
+        order: SummaryOrder,
+    ) -> std::io::Result<()> {
+        table::write_table(self, order, out)
+    }
+
+    /// Write how many tasks failed for each cause (an exit code, a signal,
+    /// a timeout...), most common first. Nothing is written if no task
+    /// failed.
+    pub fn write_failure_histogram(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        let mut causes: BTreeMap<String, usize> = BTreeMap::new();
+        for task in &self.tasks {
+            let cause = match &task.outcome {
+                TaskOutcome::Failed {
+                    exit_code: Some(code),
+                } => format!("exit code {code}"),
+                TaskOutcome::Failed { exit_code: None } => {
+                    match task.signal.and_then(rlimit::exceeded_limit) {
+                        Some(limit) => format!("exceeded the {limit}"),
+                        None => "killed by a signal".to_string(),
+                    }
+                }
+                TaskOutcome::NoOutput => "no output".to_string(),
+                TaskOutcome::WroteToStderr => "wrote to stderr".to_string(),
+                TaskOutcome::SpawnFailed(_) => "failed to start".to_string(),
+                TaskOutcome::InternalError(_) => "internal error".to_string(),
+                TaskOutcome::Interrupted => "interrupted".to_string(),
+                TaskOutcome::FilterFailed { .. } => "output filter failed".to_string(),
+                TaskOutcome::TimedOut => "timed out".to_string(),
+                TaskOutcome::Succeeded
+                | TaskOutcome::Duplicate { .. }
+                | TaskOutcome::PreconditionUnmet => continue,
+            };
+            *causes.entry(cause).or_default() += 1;
+        }
+        if causes.is_empty() {
+            return Ok(());
+        }
+
+        let mut causes: Vec<_> = causes.into_iter().collect();
+        // Stable, so that equally common causes stay in alphabetical order.
+        causes.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        writeln!(out, "Failures by cause:")?;
+        for (cause, count) in causes {
+            let tasks = if count == 1 { "task" } else { "tasks" };
+            writeln!(out, "  {cause}: {count} {tasks}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Passes on the reports of tasks as soon as they're in, rather than only
+/// with the [`RunReport`].
+#[derive(Clone)]
+struct Reporter {
+    tap: Option<Arc<tap::TapWriter>>,
+    events: Option<UnboundedSender<TaskEvent>>,
+    compact_progress: Option<Arc<CompactProgress>>,
+}
+
+impl Reporter {
+    fn report(&self, report: &TaskReport) {
+        if let Some(tap) = &self.tap {
+            tap.report(report);
+        }
+        if let Some(compact_progress) = &self.compact_progress {
+            compact_progress.task_done(report);
+        }
+        events::send(&self.events, TaskEvent::Finished(report.clone()));
+    }
+}
+
+/// A one-line account of how busy the run is, for `stats_interval`.
+fn stats_summary(state: &RunState, pool: &JobPool) -> String {
+    let RunSnapshot {
+        queued,
+        running,
+        succeeded,
+        failed,
+        ..
+    } = state.counts();
+    let permits_in_use = pool.in_use();
+    let capacity = pool.capacity();
+    format!(
+        "running: {running}, queued: {queued}, succeeded: {succeeded}, failed: {failed}, \
+         permits in use: {permits_in_use}/{capacity}"
+    )
+}
+
+/// A description of the process [`spawn_task_process`] starts for `task`,
+/// for `verbose_spawn`.
+fn describe_invocation(
+    index: usize,
+    task: &Task,
+    spawn: &SpawnOptions,
+    output: &OutputOptions,
+) -> String {
+    let program = task.shell.as_deref().unwrap_or(&spawn.shell);
+    let cwd = match &task.cwd {
+        Some(cwd) => format!("{cwd:?}"),
+        None => "the current directory".to_string(),
+    };
+    let pty = if output.pty { ", under a pty" } else { "" };
+    let mut env = match &spawn.env_passthrough {
+        Some(names) => format!("passing through only {names:?} of ptsd's environment"),
+        None => "inheriting ptsd's environment".to_string(),
+    };
+    if !task.env.is_empty() {
+        env += &format!(" and setting {:?}", task.env.keys().collect::<Vec<_>>());
+    }
+    format!(
+        "Spawning task {index}: {:?} in {cwd}{pty}, {env}",
+        [program, "-c", &task.command]
+    )
+}
+
+/// Make a task's name safe to use as part of a file name.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "-_.=,+".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The path of the log of a task with [`RunOptions::log_json`].
+fn json_log_path(log_dir: &Path, task_name: &str) -> PathBuf {
+    log_dir.join(format!("{task_name}.jsonl"))
+}
+
+/// Every log file of a task.
+fn task_log_files(log_dir: &Path, task_name: &str, json_lines: bool) -> Vec<PathBuf> {
+    if json_lines {
+        vec![json_log_path(log_dir, task_name)]
+    } else {
+        let (stdout_file_path, stderr_file_path) = log_paths(log_dir, task_name);
+        vec![stdout_file_path, stderr_file_path]
+    }
+}
+
+/// The paths of the stdout and stderr log files of a task.
+fn log_paths(log_dir: &Path, task_name: &str) -> (PathBuf, PathBuf) {
+    (
+        log_dir.join(format!("{task_name}.stdout")),
+        log_dir.join(format!("{task_name}.stderr")),
+    )
+}
+
+/// Map the index of every task that repeats an earlier one to the index of
+/// its first occurrence. Each task is paired with its index.
+fn find_duplicates(tasks: &[(usize, &Task)]) -> HashMap<usize, usize> {
+    let mut first_occurrences = HashMap::new();
+    let mut duplicates = HashMap::new();
+    for &(i, task) in tasks {
+        let first = *first_occurrences.entry(task).or_insert(i);
+        if first != i {
+            duplicates.insert(i, first);
+        }
+    }
+    duplicates
+}
+
+/// The size of a log file, or 0 if it can't be read.
+fn file_len(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+/// The logs of a failed task, under a banner, for `echo_failures`.
+async fn failure_echo(index: usize, command: &str, log_files: &[PathBuf]) -> String {
+    let mut echo = format!("==== Task {index} failed: {command}");
+    for path in log_files {
+        // The extension tells the logs apart: stdout, stderr or jsonl.
+        let stream = path.extension().map_or_else(
+            || path.to_string_lossy(),
+            |extension| extension.to_string_lossy(),
+        );
+        match tokio::fs::read(path).await {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => {
+                let output = String::from_utf8_lossy(&output);
+                echo += &format!("\n---- {stream} ----\n{}", output.trim_end_matches('\n'));
+            }
+            Err(e) => echo += &format!("\n---- {stream} could not be read: {e}"),
+        }
+    }
+    echo + "\n==== End of task " + &index.to_string()
+}
+
+/// How the processes of tasks are started, other than where their output
+/// goes.
+struct SpawnOptions {
+    shell: String,
+    env_passthrough: Option<Vec<String>>,
+    /// Start every task in a process group of its own, so that it doesn't
+    /// receive the interrupts sent to ptsd from the terminal.
+    own_process_group: bool,
+    /// The number of tasks in the run, if it's known in advance.
+    total: Option<usize>,
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+    /// Pin each task to one of these CPUs, round-robin by index.
+    cpus: Option<Vec<usize>>,
+    resource_limits: ResourceLimits,
+}
+
+/// What a task needs to restart its command (see [`RunOptions::supervise`]
+/// and [`RunOptions::relaunch_on_oom`]).
+struct Restarter {
+    task: Task,
+    task_name: String,
+    log_dir: Option<PathBuf>,
+    spawn: Arc<SpawnOptions>,
+    /// Appending to the logs of the earlier runs.
+    output: Arc<OutputOptions>,
+    max_restarts: Option<u32>,
+}
+
+impl Restarter {
+    fn spawn(&self, index: usize) -> std::io::Result<(tokio::process::Child, Option<OutputPumps>)> {
+        spawn_task_process(
+            self.log_dir.as_deref(),
+            index,
+            &self.task_name,
+            &self.task,
+            &self.spawn,
+            &self.output,
+        )
+    }
+}
+
+fn spawn_task_process(
+    log_dir: Option<&Path>,
+    index: usize,
+    task_name: &str,
+    task: &Task,
+    spawn: &SpawnOptions,
+    output: &OutputOptions,
+) -> std::io::Result<(tokio::process::Child, Option<OutputPumps>)> {
+    let shell = task.shell.as_deref().unwrap_or(&spawn.shell);
+    // A pty puts the task in a new session, and so a new process group,
+    // anyway.
+    let mut command = shell_command(
+        shell,
+        &task.command,
+        task,
+        spawn,
+        spawn.own_process_group && !output.pty,
+    );
+    command
+        .env("PTSD_TASK_INDEX", index.to_string())
+        .env("PTSD_TASK_NAME", task_name);
+    if let Some(total) = spawn.total {
+        command.env("PTSD_TASK_TOTAL", total.to_string());
+    }
+    priority::set_priority(&mut command, spawn.nice, spawn.io_priority);
+    if let Some(cpus) = &spawn.cpus {
+        affinity::pin_to_cpu(&mut command, cpus[index % cpus.len()]);
+    }
+    rlimit::set_limits(&mut command, spawn.resource_limits);
+
+    if !output.needs_pipes() {
+        let (stdout, stderr) = match log_dir {
+            Some(log_dir) => {
+                let (stdout_file_path, stderr_file_path) = log_paths(log_dir, task_name);
+                (
+                    Stdio::from(output::open_log(&stdout_file_path, output.append)?),
+                    Stdio::from(output::open_log(&stderr_file_path, output.append)?),
+                )
+            }
+            None => (Stdio::null(), Stdio::null()),
+        };
+        let child = command.stdout(stdout).stderr(stderr).spawn()?;
+        return Ok((child, None));
+    }
+
+    let stdout_sink = output.sink(log_dir, index, task_name, task, Stream::Stdout)?;
+    let stderr_sink = output.sink(log_dir, index, task_name, task, Stream::Stderr)?;
+
+    // Output filters are run by the shell, even for tasks with their own
+    // interpreter.
+    let filter = output.filter.as_ref().map(|filter| {
+        move || shell_command(&spawn.shell, filter, task, spawn, spawn.own_process_group)
+    });
+
+    if output.pty {
+        return spawn_in_pty(
+            command,
+            stdout_sink,
+            stderr_sink,
+            filter,
+            output.budget.clone(),
+        );
+    }
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let pumps = match filter {
+        Some(filter) => {
+            let (stdout_filter, stdout) = OutputFilter::start(filter(), stdout)?;
+            let (stderr_filter, stderr) = OutputFilter::start(filter(), stderr)?;
+            OutputPumps::start(
+                stdout,
+                stdout_sink,
+                stderr,
+                stderr_sink,
+                vec![stdout_filter, stderr_filter],
+                output.budget.clone(),
+            )
+        }
+        None => OutputPumps::start(
+            stdout,
+            stdout_sink,
+            stderr,
+            stderr_sink,
+            Vec::new(),
+            output.budget.clone(),
+        ),
+    };
+    Ok((child, Some(pumps)))
+}
+
+/// A command running `script` with `shell`, in the environment of `task`.
+fn shell_command(
+    shell: &str,
+    script: &str,
+    task: &Task,
+    spawn: &SpawnOptions,
+    own_process_group: bool,
+) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(shell);
+    command.arg("-c").arg(script).stdin(Stdio::null());
+    // Aborting the run (e.g. when the disk is full) kills the running tasks
+    // by dropping them.
+    command.kill_on_drop(true);
+    if let Some(cwd) = &task.cwd {
+        command.current_dir(cwd);
+    }
+    if own_process_group {
+        set_own_process_group(&mut command);
+    }
+    if let Some(names) = &spawn.env_passthrough {
+        command.env_clear();
+        for name in names {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+    command.envs(&task.env);
+    command
+}
+
+#[cfg(unix)]
+fn set_own_process_group(command: &mut tokio::process::Command) {
+    // SAFETY: `setpgid` is async-signal-safe, and only affects the child.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn set_own_process_group(_command: &mut tokio::process::Command) {}
+
+/// Spawn `command` with a pty as its stdout and stderr. Everything it prints
+/// goes to `stdout_sink`, leaving the stderr log empty.
+#[cfg(unix)]
+fn spawn_in_pty(
+    mut command: tokio::process::Command,
+    stdout_sink: Box<dyn LogSink>,
+    stderr_sink: Box<dyn LogSink>,
+    filter: Option<impl Fn() -> tokio::process::Command>,
+    budget: Option<Arc<OutputBudget>>,
+) -> std::io::Result<(tokio::process::Child, Option<OutputPumps>)> {
+    let pty = pty::Pty::open()?;
+    command.stdout(pty.slave.try_clone()?).stderr(pty.slave);
+    // SAFETY: the hook only makes async-signal-safe calls.
+    unsafe {
+        command.pre_exec(pty::make_controlling_terminal);
+    }
+    let child = command.spawn()?;
+    // Close our copies of the slave side, so that reading the master side
+    // ends once the child exits.
+    drop(command);
+
+    let output = pty::PtyReader::new(pty.master);
+    let pumps = match filter {
+        Some(filter) => {
+            let (filter, output) = OutputFilter::start(filter(), output)?;
+            OutputPumps::start(
+                output,
+                stdout_sink,
+                tokio::io::empty(),
+                stderr_sink,
+                vec![filter],
+                budget,
+            )
+        }
+        None => OutputPumps::start(
+            output,
+            stdout_sink,
+            tokio::io::empty(),
+            stderr_sink,
+            Vec::new(),
+            budget,
+        ),
+    };
+    Ok((child, Some(pumps)))
+}
+
+#[cfg(not(unix))]
+fn spawn_in_pty(
+    _command: tokio::process::Command,
+    _stdout_sink: Box<dyn LogSink>,
+    _stderr_sink: Box<dyn LogSink>,
+    _filter: Option<impl Fn() -> tokio::process::Command>,
+    _budget: Option<Arc<OutputBudget>>,
+) -> std::io::Result<(tokio::process::Child, Option<OutputPumps>)> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "running commands under a pty is only supported on Unix",
+    ))
+}
+
+/// How many of the indices below `total` fall in one of the `ranges`.
+fn count_selected(ranges: &[RangeInclusive<usize>], total: usize) -> usize {
+    let mut ranges: Vec<_> = ranges
+        .iter()
+        .filter(|range| *range.start() < total)
+        .map(|range| *range.start()..(*range.end()).min(total - 1) + 1)
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    let mut count = 0;
+    let mut counted_up_to = 0;
+    for range in ranges {
+        let start = range.start.max(counted_up_to);
+        if start < range.end {
+            count += range.end - start;
+            counted_up_to = range.end;
+        }
+    }
+    count
+}
+
+/// Run all of the commands in `options`, at most `options.jobs` at a time,
+/// and report how each of them fared.
+pub async fn run_commands(options: RunOptions) -> Result<RunReport, SetupError> {
+    let selected = |i: usize| {
+        options
+            .select
+            .as_ref()
+            .is_none_or(|select| select.iter().any(|range| range.contains(&i)))
+    };
+    let selected_tasks: Vec<_> = options
+        .tasks
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| selected(i))
+        .collect();
+    // How many tasks there are, streamed ones included, if that's known.
+    let total_tasks = match &options.streamed_tasks {
+        Some(stream) => stream.len().map(|len| options.tasks.len() + len),
+        None => Some(options.tasks.len()),
+    };
+    let selected_total = total_tasks.map(|total| match &options.select {
+        Some(select) => count_selected(select, total),
+        None => total,
+    });
+    // There's no use for more jobs than tasks; limiting them keeps ramping
+    // up, jitter and the reported concurrency in line with what can run.
+    let jobs = match selected_total {
+        Some(total) => options.jobs.get().min(total).max(1),
+        None => options.jobs.get(),
+    };
+
+    if options.require_shell_exists {
+        precheck::check_shell_exists(&options.shell).map_err(|problem| {
+            SetupError::MissingShell {
+                shell: options.shell.clone(),
+                problem,
+            }
+        })?;
+    }
+
+    if options.prelaunch_check {
+        let problems = precheck::prelaunch_check(&options.shell, &selected_tasks, jobs).await;
+        if !problems.is_empty() {
+            return Err(SetupError::PrelaunchCheck(
+                problems
+                    .into_iter()
+                    .map(|(i, problem)| (i, options.tasks[i].command.clone(), problem))
+                    .collect(),
+            ));
+        }
+    }
+
+    if let Some(probe) = &options.precheck {
+        if let Err((exit_code, output)) = precheck::run_probe(&options.shell, probe).await {
+            return Err(SetupError::ProbeFailed {
+                command: probe.clone(),
+                exit_code,
+                output,
+            });
+        }
+    }
+
+    let status_listener = match options.serve {
+        Some(addr) => Some(
+            tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|source| SetupError::Serve { addr, source })?,
+        ),
+        None => None,
+    };
+
+    let log_dir = match options.log_dir {
+        _ if options.no_log => None,
+        Some(log_dir) => {
+            std::fs::create_dir_all(&log_dir).map_err(|source| SetupError::LogDir {
+                path: Some(log_dir.clone()),
+                source,
+            })?;
+            Some(log_dir)
+        }
+        None => Some(
+            tempfile::tempdir()
+                .map_err(|source| SetupError::LogDir { path: None, source })?
+                .into_path(),
+        ),
+    };
+
+    let multi_progress_bar = if options.disable_progress {
+        None
+    } else {
+        Some(MultiProgress::with_draw_target(
+            match options.progress_target {
+                ProgressTarget::Stdout => {
+                    ProgressDrawTarget::stdout_with_hz(options.progress_fps.get())
+                }
+                ProgressTarget::Stderr => {
+                    ProgressDrawTarget::stderr_with_hz(options.progress_fps.get())
+                }
+            },
+        ))
+    };
+
+    let compact_progress = options.compact_progress
+        || (multi_progress_bar.is_some()
+            && !options.full_progress
+            && progress::terminal_rows(options.progress_target)
+                .is_some_and(|rows| jobs > rows.saturating_sub(2)));
+    // The progress bars of the tasks themselves, unless they're summed up
+    // by `compact_progress`.
+    let task_progress_bars = multi_progress_bar.as_ref().filter(|_| !compact_progress);
+
+    let messages = Messages {
+        writer: options.messages.clone(),
+        progress_bars: multi_progress_bar.clone(),
+    };
+
+    let styles = init_progress_styles(&options.progress_glyphs);
+
+    // Calculate the character-width of the largest command index.
+    // This is used  to align the log file names so they would be sortable by
+    // command order.
+    let width = (total_tasks.unwrap_or(options.tasks.len()) as f32).log10() as usize + 1;
+
+    if let (None, Some(log_dir)) = (&options.output_sink, &log_dir) {
+        messages.line(format!("Writing standard outputs to {log_dir:?}"));
+    }
+    if let Some(addr) = status_listener
+        .as_ref()
+        .and_then(|listener| listener.local_addr().ok())
+    {
+        messages.line(format!("Serving the state of the run on http://{addr}/"));
+    }
+    if let (Some(link), Some(log_dir)) = (&options.latest_symlink, &log_dir) {
+        if let Err(e) = link::update_latest_link(link, log_dir) {
+            messages.line(format!(
+                "Failed pointing {link:?} at the log directory: {e}"
+            ));
+        }
+    }
+
+    let check_duplicates = options.warn_duplicates || options.dedup;
+    let mut duplicates = if check_duplicates {
+        find_duplicates(&selected_tasks)
+    } else {
+        HashMap::new()
+    };
+    let warn_duplicate = |i: usize, first: usize, command: &str| {
+        let action = if options.dedup {
+            "skipping it"
+        } else {
+            "running it anyway"
+        };
+        messages.line(format!(
+            "Task {i} is a duplicate of task {first} ({command:?}), {action}"
+        ));
+    };
+    let mut duplicate_indices: Vec<_> = duplicates.iter().collect();
+    duplicate_indices.sort();
+    for (&i, &first) in duplicate_indices {
+        warn_duplicate(i, first, &options.tasks[i].command);
+    }
+    // Streamed tasks are checked for duplicates as they arrive, against
+    // every task before them.
+    let mut first_occurrences = HashMap::new();
+    if check_duplicates && options.streamed_tasks.is_some() {
+        for &(i, task) in &selected_tasks {
+            first_occurrences.entry(task.clone()).or_insert(i);
+        }
+    }
+
+    let mut reports = Vec::new();
+    let mut run_files = HashSet::new();
+    let mut log_files_by_task = HashMap::new();
+
+    if options.binary_safe && (options.strip_ansi_logs || options.pty || options.log_json) {
+        messages.line(
+            "Binary-safe logging is on; output will not be stripped of ANSI sequences, \
+             run under a pty or logged as JSON",
+        );
+    }
+    let combined_log = match &options.combined_log {
+        Some(path) => Some(Arc::new(CombinedLog::create(path).map_err(|source| {
+            SetupError::CombinedLog {
+                path: path.clone(),
+                source,
+            }
+        })?)),
+        None => None,
+    };
+    let output_options = OutputOptions {
+        deferred_limit: options
+            .output_on_failure_only
+            .then_some(options.output_buffer_limit),
+        budget: options
+            .max_concurrent_output_bytes
+            .map(|max| Arc::new(OutputBudget::new(max))),
+        strip_ansi: options.strip_ansi_logs && !options.binary_safe,
+        pty: options.pty && !options.binary_safe,
+        disk_full: options
+            .abort_on_disk_full
+            .then(|| Arc::new(Alarm::default())),
+        custom_sink: options.output_sink.clone(),
+        events: options.events.clone(),
+        filter: options.output_filter.clone(),
+        dedupe: options.dedupe_output,
+        json_lines: options.log_json && !options.binary_safe,
+        count_output: log_dir.is_none()
+            && (options.fail_on_empty_output.is_some() || options.fail_on_stderr),
+        append: false,
+        combined_log,
+        max_line_length: options.max_line_length,
+    };
+    let appending_output_options = Arc::new(OutputOptions {
+        append: true,
+        ..output_options.clone()
+    });
+    let disk_full = output_options.disk_full.clone();
+    let failure_pause = options
+        .pause_on_failure
+        .then(FailurePause::interactive)
+        .flatten()
+        .map(Arc::new);
+    let cpus = if options.cpu_affinity {
+        match affinity::allowed_cpus() {
+            Ok(cpus) if !cpus.is_empty() => Some(cpus),
+            Ok(_) => None,
+            Err(e) => {
+                messages.line(format!("Not pinning the tasks to CPUs: {e}"));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let spawn_options = Arc::new(SpawnOptions {
+        shell: options.shell.clone(),
+        env_passthrough: options.env_passthrough.clone(),
+        own_process_group: options.graceful_interrupts,
+        total: total_tasks,
+        nice: options.nice,
+        io_priority: options.io_priority,
+        cpus,
+        resource_limits: options.resource_limits,
+    });
+
+    let run_start = Instant::now();
+    let concurrent_jobs = Arc::new(JobPool::new(if options.ramp.is_some() { 1 } else { jobs }));
+    let ramp = options
+        .ramp
+        .map(|ramp| AbortOnDrop::spawn(ramp_up(concurrent_jobs.clone(), jobs, ramp)));
+    let jitter = options
+        .jobs_min
+        .filter(|_| options.ramp.is_none())
+        .map(|jobs_min| {
+            AbortOnDrop::spawn(jitter(
+                concurrent_jobs.clone(),
+                jobs_min.get().min(jobs),
+                jobs,
+                JOBS_JITTER_INTERVAL,
+            ))
+        });
+    let run_state = Arc::new(RunState::new(&options.tasks, selected));
+
+    let reporter = Reporter {
+        tap: options
+            .tap
+            .then(|| Arc::new(tap::TapWriter::start(selected_total))),
+        events: options.events.clone(),
+        compact_progress: multi_progress_bar
+            .as_ref()
+            .filter(|_| compact_progress)
+            .map(|multi_progress_bar| {
+                Arc::new(CompactProgress::new(
+                    multi_progress_bar,
+                    selected_total.unwrap_or(selected_tasks.len()),
+                    &options.progress_glyphs,
+                    options.tick_interval,
+                    options.max_message_width,
+                ))
+            }),
+    };
+
+    let status_server = status_listener
+        .map(|listener| AbortOnDrop::spawn(serve::serve(listener, run_state.clone())));
+
+    let stats_reporter = options.stats_interval.map(|stats_interval| {
+        let run_state = run_state.clone();
+        let concurrent_jobs = concurrent_jobs.clone();
+        let messages = messages.clone();
+        AbortOnDrop::spawn(async move {
+            let mut interval = tokio::time::interval(stats_interval);
+            // The first tick completes immediately, and there's nothing to report yet.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                messages.line(stats_summary(&run_state, &concurrent_jobs));
+            }
+        })
+    });
+
+    let progress_writer = options.progress_to.clone().map(|path| {
+        let run_state = run_state.clone();
+        let messages = messages.clone();
+        AbortOnDrop::spawn(async move {
+            let mut interval = tokio::time::interval(PROGRESS_FILE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = run_state.write_snapshot(&path) {
+                    messages.line(format!("Failed writing the progress file {path:?}: {e}"));
+                }
+            }
+        })
+    });
+
+    // Raised on the first and second interrupt, respectively.
+    let (drain, kill) = if options.graceful_interrupts {
+        (
+            Some(Arc::new(Alarm::default())),
+            Some(Arc::new(Alarm::default())),
+        )
+    } else {
+        (None, None)
+    };
+    let interrupt_handler = drain.clone().zip(kill.clone()).map(|(drain, kill)| {
+        let messages = messages.clone();
+        AbortOnDrop::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            messages.line(
+                "Interrupted, waiting for the running tasks to finish; \
+                 interrupt again to kill them",
+            );
+            drain.raise();
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            messages.line("Interrupted again, killing the running tasks");
+            kill.raise();
+        })
+    });
+
+    let mut preconditions =
+        precheck::check_preconditions(&selected_tasks, &spawn_options, jobs).await;
+    drop(selected_tasks);
+
+    let mut spawn_rate = options.spawn_rate.map(RateLimiter::new);
+    let mut memory_gate = match options.reserve_memory_per_task {
+        Some(reserve) => match pool::available_memory() {
+            Ok(_) => Some(MemoryGate::new(reserve)),
+            Err(e) => {
+                messages.line(format!("Not holding tasks back for memory: {e}"));
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Where the logs of the next task go: `log_dir`, until it fills up.
+    let mut task_log_dir = log_dir.clone();
+    let mut log_dir_fallback = None;
+
+    // Convert the collected commands into async join-handles
+    let mut tasks = Vec::new();
+    let mut not_started = Vec::new();
+    let given_tasks = options.tasks.len();
+    let mut pending_tasks = TaskSource::new(options.tasks, options.streamed_tasks).await;
+    loop {
+        let next = tokio::select! {
+            biased;
+            () = alarm::wait_for(&disk_full) => None,
+            () = alarm::wait_for(&drain) => None,
+            next = pending_tasks.next() => next,
+        };
+        let Some((i, task)) = next else {
+            break;
+        };
+        if !selected(i) {
+            continue;
+        }
+        if i >= given_tasks {
+            run_state.add(i, &task);
+            if let (Some(compact_progress), None) = (&reporter.compact_progress, selected_total) {
+                compact_progress.add_task();
+            }
+            if check_duplicates {
+                match first_occurrences.entry(task.clone()) {
+                    Entry::Occupied(first) => {
+                        warn_duplicate(i, *first.get(), &task.command);
+                        duplicates.insert(i, *first.get());
+                    }
+                    Entry::Vacant(first) => {
+                        first.insert(i);
+                    }
+                }
+            }
+            if task.only_if.is_some() {
+                preconditions
+                    .extend(precheck::check_preconditions(&[(i, &task)], &spawn_options, 1).await);
+            }
+        }
+        if let (true, Some(&first)) = (options.dedup, duplicates.get(&i)) {
+            run_state.set(i, TaskState::Skipped);
+            let report = TaskClock::start().report(i, task, TaskOutcome::Duplicate { of: first });
+            reporter.report(&report);
+            reports.push(report);
+            continue;
+        }
+        match preconditions.remove(&i) {
+            Some(Ok(true)) | None => {}
+            Some(Ok(false)) => {
+                run_state.set(i, TaskState::Skipped);
+                let report = TaskClock::start().report(i, task, TaskOutcome::PreconditionUnmet);
+                reporter.report(&report);
+                reports.push(report);
+                continue;
+            }
+            Some(Err(e)) => {
+                run_state.set(i, TaskState::Failed);
+                let report = TaskClock::start().report(
+                    i,
+                    task,
+                    TaskOutcome::SpawnFailed(format!("failed running the precondition: {e}")),
+                );
+                reporter.report(&report);
+                reports.push(report);
+                continue;
+            }
+        }
+
+        // Wait for a permit to be acquired, and for the spawn rate and the
+        // available memory to allow another task, before starting.
+        let permit = tokio::select! {
+            biased;
+            () = alarm::wait_for(&disk_full) => {
+                not_started.push((i, task));
+                break;
+            }
+            () = alarm::wait_for(&drain) => {
+                not_started.push((i, task));
+                break;
+            }
+            permit = async {
+                let permit = concurrent_jobs.acquire().await;
+                if let Some(spawn_rate) = &mut spawn_rate {
+                    spawn_rate.acquire().await;
+                }
+                if let Some(memory_gate) = &mut memory_gate {
+                    memory_gate.acquire(&run_state).await;
+                }
+                if let Some(failure_pause) = &failure_pause {
+                    failure_pause.wait_while_paused().await;
+                }
+                permit
+            } => permit,
+        };
+
+        let clock = TaskClock::start();
+
+        // Cloning the styles since they're consumed by-move by every bar.
+        let styles = styles.clone();
+
+        let pb = task_progress_bars.map(|multi_progress_bar| {
+            let pb = multi_progress_bar.add(ProgressBar::new_spinner());
+            pb.set_style(styles.progress);
+            if let Some(tick_interval) = options.tick_interval {
+                pb.enable_steady_tick(tick_interval);
+            }
+            let message = match &options.message_template {
+                Some(template) => Cow::Owned(render_message(template, i, &task)),
+                None => Cow::Borrowed(task.command.as_str()),
+            };
+            let mut message = match options.max_message_width {
+                Some(max_width) => truncate_to_width(&message, max_width).into_owned(),
+                None => message.into_owned(),
+            };
+            if options.show_start_time {
+                message += &format!(
+                    " (started {})",
+                    humantime::format_rfc3339_seconds(clock.started_at)
+                );
+            }
+            pb.set_message(message);
+            pb.set_prefix(i.to_string());
+            pb
+        });
+
+        run_state.set(i, TaskState::Running);
+        let task_name = match &task.name {
+            Some(name) => format!("{i:0width$}-{}", sanitize_file_name(name)),
+            None => format!("{i:0width$}"),
+        };
+        if options.verbose_spawn {
+            messages.line(describe_invocation(
+                i,
+                &task,
+                &spawn_options,
+                &output_options,
+            ));
+        }
+        // The progress bars already show which tasks are running.
+        if options.print_command_on_start && multi_progress_bar.is_none() {
+            messages.line(format!("#{task_name} started: {}", task.command));
+        }
+        events::send(
+            &options.events,
+            TaskEvent::Started {
+                index: i,
+                command: task.command.clone(),
+            },
+        );
+        let spawn = |log_dir: Option<&Path>| {
+            spawn_task_process(
+                log_dir,
+                i,
+                &task_name,
+                &task,
+                &spawn_options,
+                &output_options,
+            )
+        };
+        let mut spawned = spawn(task_log_dir.as_deref());
+        let full_log_dir = match (&spawned, &task_log_dir, &log_dir_fallback) {
+            (Err(e), Some(log_dir), None) if e.kind() == std::io::ErrorKind::StorageFull => {
+                Some(log_dir.clone())
+            }
+            _ => None,
+        };
+        if let (Some(full_log_dir), Some(fallback)) = (full_log_dir, &options.log_dir_fallback) {
+            match std::fs::create_dir_all(fallback) {
+                Ok(()) => {
+                    messages.line(format!(
+                        "{full_log_dir:?} is full, writing the logs of task {i} on to {fallback:?}"
+                    ));
+                    task_log_dir = Some(fallback.clone());
+                    log_dir_fallback = Some((i, fallback.clone()));
+                    spawned = spawn(task_log_dir.as_deref());
+                }
+                Err(e) => messages.line(format!(
+                    "Failed creating the fallback log directory {fallback:?}: {e}"
+                )),
+            }
+        }
+        let task_log_files = task_log_dir.as_deref().map_or_else(Vec::new, |log_dir| {
+            task_log_files(log_dir, &task_name, output_options.json_lines)
+        });
+        run_files.extend(task_log_files.iter().cloned());
+        log_files_by_task.insert(i, task_log_files.clone());
+        let (proc, pumps) = match spawned {
+            Ok(proc) => proc,
+            Err(e) => {
+                if let Some(disk_full) = &disk_full {
+                    output::check_disk_full(disk_full, &e);
+                }
+                if let Some(pb) = pb {
+                    pb.set_style(styles.fail);
+                    pb.finish();
+                }
+                run_state.set(i, TaskState::Failed);
+                let report = TaskReport {
+                    log_files: task_log_files,
+                    ..clock.report(i, task, TaskOutcome::SpawnFailed(e.to_string()))
+                };
+                reporter.report(&report);
+                reports.push(report);
+                continue;
+            }
+        };
+
+        let pid = proc.id();
+        if let (true, Some(pid)) = (options.verbose_spawn, pid) {
+            messages.line(format!("Task {i} is running as PID {pid}"));
+        }
+
+        let run_state = run_state.clone();
+        let reporter = reporter.clone();
+        let messages = messages.clone();
+        let reported_task = task.clone();
+        let fail_on_empty_output = options.fail_on_empty_output;
+        let fail_on_stderr = options.fail_on_stderr;
+        let fsync_logs = options.fsync_logs;
+        let ensure_final_newline = options.ensure_final_newline && !options.binary_safe;
+        let failure_pause = failure_pause.clone();
+        let drain = drain.clone();
+        let timeout = task.timeout;
+        let echo_failures = options.echo_failures;
+        let supervise = options.supervise;
+        let relaunch_on_oom = options.relaunch_on_oom;
+        let job_pool = concurrent_jobs.clone();
+        let restarter = (supervise || relaunch_on_oom).then(|| Restarter {
+            task: task.clone(),
+            task_name: task_name.clone(),
+            log_dir: task_log_dir.clone(),
+            spawn: spawn_options.clone(),
+            output: appending_output_options.clone(),
+            max_restarts: options.max_restarts,
+        });
+        // Tasks under a pty lead a session, and so a process group, of their
+        // own too.
+        let own_group = spawn_options.own_process_group || output_options.pty;
+        let handle = AbortOnDrop::spawn(async move {
+            let (mut proc, mut pumps, mut pid) = (proc, pumps, pid);
+            // Whichever way the task ends, even by being aborted, nothing it
+            // started is left running.
+            let mut group = ProcessGroup::new(pid, own_group);
+            let mut permit = Some(permit);
+            let mut restarts = 0;
+            let mut relaunched = false;
+            let mut signal;
+            let outcome = loop {
+                let status = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, proc.wait()).await.ok(),
+                    None => Some(proc.wait().await),
+                };
+                signal = match &status {
+                    Some(Ok(res)) => rlimit::exit_signal(res),
+                    _ => None,
+                };
+                if let Some(limit) = signal.and_then(rlimit::exceeded_limit) {
+                    messages.line(format!(
+                        "Task {task_name} was killed for exceeding its {limit}"
+                    ));
+                }
+                let mut outcome = match status {
+                    Some(Ok(res)) if res.success() => TaskOutcome::Succeeded,
+                    Some(Ok(res)) => TaskOutcome::Failed {
+                        exit_code: res.code(),
+                    },
+                    Some(Err(e)) => {
+                        messages.line(format!("Failed waiting for task {task_name}: {e}"));
+                        TaskOutcome::Failed { exit_code: None }
+                    }
+                    None => {
+                        group.kill();
+                        if let Err(e) = proc.kill().await {
+                            messages.line(format!("Failed killing task {task_name}: {e}"));
+                        }
+                        TaskOutcome::TimedOut
+                    }
+                };
+                let drained = match pumps {
+                    Some(pumps) => match pumps.drain().await {
+                        Ok(drained) => Some(drained),
+                        Err(e) => {
+                            messages.line(format!(
+                                "Failed reading the output of task {task_name}: {e}"
+                            ));
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                if let (Some(status), true) = (
+                    drained.as_ref().and_then(|drained| drained.filter_failure),
+                    outcome.is_success(),
+                ) {
+                    outcome = TaskOutcome::FilterFailed {
+                        exit_code: status.code(),
+                    };
+                }
+                if (fail_on_empty_output.is_some() || fail_on_stderr) && outcome.is_success() {
+                    let (stdout_len, stderr_len) = match (&drained, task_log_files.as_slice()) {
+                        (Some(drained), _) => (drained.stdout_len, drained.stderr_len),
+                        (None, [stdout_file_path, stderr_file_path]) => {
+                            (file_len(stdout_file_path), file_len(stderr_file_path))
+                        }
+                        (None, _) => (0, 0),
+                    };
+                    if fail_on_stderr && stderr_len > 0 {
+                        outcome = TaskOutcome::WroteToStderr;
+                    } else if fail_on_empty_output
+                        .is_some_and(|policy| policy.is_silent(stdout_len, stderr_len))
+                    {
+                        outcome = TaskOutcome::NoOutput;
+                    }
+                }
+                let success = outcome.is_success();
+                if let Some(drained) = drained {
+                    if let Err(e) = drained.finish(success) {
+                        messages.line(format!(
+                            "Failed writing the output of task {task_name}: {e}"
+                        ));
+                    }
+                }
+                let Some(restarter) = &restarter else {
+                    break outcome;
+                };
+                if alarm::is_raised(&drain) {
+                    break outcome;
+                }
+                let oom_killed = match outcome {
+                    TaskOutcome::Failed { exit_code } => {
+                        rlimit::looks_oom_killed(signal, exit_code)
+                    }
+                    _ => false,
+                };
+                if relaunch_on_oom && oom_killed && !relaunched {
+                    relaunched = true;
+                    messages.line(format!(
+                        "Task {task_name} looks like it ran out of memory, restarting it \
+                         with fewer tasks alongside it"
+                    ));
+                    // Letting go of its slot first, so that tasks waiting
+                    // on each other for a second slot can't get stuck.
+                    drop(permit.take());
+                    permit = tokio::select! {
+                        permit = job_pool.acquire_many(2) => Some(permit),
+                        () = alarm::wait_for(&drain) => break outcome,
+                    };
+                    restarts += 1;
+                } else {
+                    if !supervise || restarter.max_restarts.is_some_and(|max| restarts >= max) {
+                        break outcome;
+                    }
+                    tokio::select! {
+                        () = tokio::time::sleep(RESTART_DELAY) => {}
+                        () = alarm::wait_for(&drain) => break outcome,
+                    }
+                    restarts += 1;
+                    messages.line(format!(
+                        "Task {task_name} exited, restarting it (restart {restarts})"
+                    ));
+                }
+                match restarter.spawn(i) {
+                    Ok((restarted, restarted_pumps)) => {
+                        pid = restarted.id();
+                        group = ProcessGroup::new(pid, own_group);
+                        (proc, pumps) = (restarted, restarted_pumps);
+                        if let Some(pb) = &pb {
+                            pb.set_prefix(format!("{i} (restart {restarts})"));
+                        }
+                    }
+                    Err(e) => break TaskOutcome::SpawnFailed(e.to_string()),
+                }
+            };
+            let success = outcome.is_success();
+            if ensure_final_newline {
+                if let Err(e) = output::ensure_final_newlines(&task_log_files) {
+                    messages.line(format!(
+                        "Failed fixing up the final newline of the logs of task {task_name}: {e}"
+                    ));
+                }
+            }
+            // Whichever way the output was written, it is complete by now;
+            // the task isn't reported as done before its logs are.
+            if fsync_logs {
+                if let Err(e) = output::sync_logs(&task_log_files) {
+                    messages.line(format!(
+                        "Failed syncing the output of task {task_name}: {e}"
+                    ));
+                }
+            }
+            run_state.set(
+                i,
+                if success {
+                    TaskState::Succeeded
+                } else {
+                    TaskState::Failed
+                },
+            );
+            if let Some(pb) = pb {
+                if success {
+                    pb.set_style(styles.done);
+                } else {
+                    pb.set_style(styles.fail);
+                }
+                pb.finish();
+            }
+            if echo_failures && !success && !task_log_files.is_empty() {
+                messages.line(failure_echo(i, &reported_task.command, &task_log_files).await);
+            }
+            if let (Some(failure_pause), false) = (&failure_pause, success) {
+                let details = if task_log_files.is_empty() {
+                    format!("Task {i} failed")
+                } else {
+                    let files: Vec<_> = task_log_files
+                        .iter()
+                        .map(|path| format!("{path:?}"))
+                        .collect();
+                    format!("Task {i} failed; its output is in {}", files.join(" and "))
+                };
+                failure_pause.pause_once(&messages, &details, &drain).await;
+            }
+            drop(permit);
+            // Report the process exit code as task output
+            let report = TaskReport {
+                pid,
+                restarts,
+                signal,
+                log_files: task_log_files,
+                ..clock.report(i, reported_task, outcome)
+            };
+            reporter.report(&report);
+            report
+        });
+        tasks.push((i, task, clock, handle));
+    }
+    not_started.extend(pending_tasks.remaining().filter(|&(i, _)| selected(i)));
+    if alarm::is_raised(&drain) || alarm::is_raised(&disk_full) {
+        for (i, task) in not_started {
+            let report = TaskClock::start().report(i, task, TaskOutcome::Interrupted);
+            reporter.report(&report);
+            reports.push(report);
+        }
+    }
+
+    // Await the tasks and record their outcomes
+    let mut tasks = tasks.into_iter();
+    while let Some((task_index, task, clock, mut handle)) = tasks.next() {
+        let result = tokio::select! {
+            biased;
+            () = alarm::wait_for(&disk_full) => None,
+            () = alarm::wait_for(&kill) => None,
+            result = &mut handle => Some(result),
+        };
+        let Some(result) = result else {
+            // Aborting the tasks drops their futures, which kills their
+            // processes, and their process groups with them.
+            let killed = std::iter::once((task_index, task, clock, handle)).chain(tasks);
+            for (task_index, task, clock, handle) in killed {
+                handle.abort();
+                run_state.set(task_index, TaskState::Failed);
+                let report = TaskReport {
+                    log_files: log_files_by_task[&task_index].clone(),
+                    ..clock.report(task_index, task, TaskOutcome::Interrupted)
+                };
+                reporter.report(&report);
+                reports.push(report);
+            }
+            break;
+        };
+        match result {
+            Err(join_err) => {
+                messages.line(format!("Failed joining task {task_index}: {join_err:?}"));
+                run_state.set(task_index, TaskState::Failed);
+                let report = TaskReport {
+                    log_files: log_files_by_task[&task_index].clone(),
+                    ..clock.report(
+                        task_index,
+                        task,
+                        TaskOutcome::InternalError(join_err.to_string()),
+                    )
+                };
+                reporter.report(&report);
+                reports.push(report);
+            }
+            Ok(report) => reports.push(report),
+        }
+    }
+    reports.sort_by_key(|report| report.index);
+
+    if let Some(tap) = &reporter.tap {
+        tap.finish();
+    }
+    if let Some(compact_progress) = &reporter.compact_progress {
+        compact_progress.finish();
+    }
+    if let Some(stats_reporter) = stats_reporter {
+        stats_reporter.abort();
+    }
+    if let Some(ramp) = ramp {
+        ramp.abort();
+    }
+    if let Some(jitter) = jitter {
+        jitter.abort();
+    }
+    if let Some(interrupt_handler) = interrupt_handler {
+        interrupt_handler.abort();
+    }
+    if let Some(status_server) = status_server {
+        status_server.abort();
+    }
+    if let (Some(progress_writer), Some(path)) = (progress_writer, &options.progress_to) {
+        progress_writer.abort();
+        // Make sure the file ends up reflecting the final state of the run.
+        if let Err(e) = run_state.write_snapshot(path) {
+            messages.line(format!("Failed writing the progress file {path:?}: {e}"));
+        }
+    }
+
+    if let (Some(max_size), Some(log_dir)) = (options.max_log_dir_size, &log_dir) {
+        match prune::prune_log_dir(log_dir, max_size, &run_files) {
+            Ok(size) if size > max_size => messages.line(format!(
+                "The logs of this run alone take {size} bytes, more than the {max_size} bytes allowed in {log_dir:?}"
+            )),
+            Ok(_) => {}
+            Err(e) => messages.line(format!("Failed pruning the log directory {log_dir:?}: {e}")),
+        }
+    }
+
+    if let (true, Some(log_dir)) = (options.dedupe_logs, &log_dir) {
+        match hardlink::link_identical_logs(&run_files) {
+            Ok(linked) if linked.files > 0 => messages.line(format!(
+                "Replaced {} log files with links to identical ones, saving {} bytes",
+                linked.files, linked.bytes
+            )),
+            Ok(_) => {}
+            Err(e) => messages.line(format!("Failed deduplicating the logs in {log_dir:?}: {e}")),
+        }
+    }
+
+    // The logs are cut short, and there'd be no room for the checksums.
+    if let (true, Some(log_dir), false) = (
+        options.checksum_logs,
+        &log_dir,
+        alarm::is_raised(&disk_full),
+    ) {
+        if let Err(e) = manifest::write_manifest(log_dir, &run_files) {
+            messages.line(format!(
+                "Failed writing the checksums of the logs in {log_dir:?}: {e}"
+            ));
+        }
+    }
+
+    let report = RunReport {
+        log_dir,
+        log_dir_fallback,
+        tasks: reports,
+        duration: run_start.elapsed(),
+        interrupted: alarm::is_raised(&drain),
+        disk_full: alarm::is_raised(&disk_full),
+    };
+    if let Some(path) = &options.junit_report {
+        if let Err(e) = junit::write_junit(path, &report, &log_files_by_task) {
+            messages.line(format!("Failed writing the JUnit report to {path:?}: {e}"));
+        }
+    }
+    events::send(
+        &options.events,
+        TaskEvent::RunComplete {
+            success: report.success(),
+            interrupted: report.interrupted,
+        },
+    );
+    Ok(report)
+}
+
+/// Like [`run_commands`], but also hands back a stream of the run's events,
+/// in place of any `options.events`. The stream ends once the run does.
+pub fn run_commands_with_events(
+    options: RunOptions,
+) -> (
+    UnboundedReceiver<TaskEvent>,
+    impl Future<Output = Result<RunReport, SetupError>>,
+) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let run = run_commands(RunOptions {
+        events: Some(sender),
+        ..options
+    });
+    (receiver, run)
+}
@@ -0,0 +1,35 @@
+//! Keeping a stable path pointing at the log directory of the latest run.
+
+use std::io;
+use std::path::Path;
+
+/// Point `link` at `log_dir`, replacing whatever `link` pointed at before.
+///
+/// The new link is created next to `link` and renamed over it, so `link`
+/// always points at either the previous or the new log directory. Where
+/// symlinks aren't available, `link` is a file holding the path instead.
+pub(crate) fn update_latest_link(link: &Path, log_dir: &Path) -> io::Result<()> {
+    let log_dir = log_dir.canonicalize()?;
+    let file_name = link
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the link must name a file"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp_link = link.with_file_name(temp_name);
+
+    make_link(&log_dir, &temp_link)?;
+    std::fs::rename(&temp_link, link).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_link);
+    })
+}
+
+#[cfg(unix)]
+fn make_link(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn make_link(target: &Path, link: &Path) -> io::Result<()> {
+    std::fs::write(link, target.to_string_lossy().as_bytes())
+}
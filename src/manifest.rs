@@ -0,0 +1,32 @@
+//! A checksum manifest of the logs of a run, for telling whether they were
+//! altered after the fact.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the manifest file, inside the log directory.
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST.sha256";
+
+pub(crate) fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Write the SHA-256 of each of `log_files` that exists to the manifest in
+/// `log_dir`, in the format of `sha256sum`, so that `sha256sum -c` can check
+/// it from within the log directory.
+pub(crate) fn write_manifest(log_dir: &Path, log_files: &HashSet<PathBuf>) -> io::Result<()> {
+    let mut log_files: Vec<_> = log_files.iter().filter(|path| path.exists()).collect();
+    log_files.sort();
+
+    let mut manifest = String::new();
+    for path in log_files {
+        let name = path.strip_prefix(log_dir).unwrap_or(path);
+        manifest += &format!("{}  {}\n", sha256_file(path)?, name.display());
+    }
+    File::create(log_dir.join(MANIFEST_FILE_NAME))?.write_all(manifest.as_bytes())
+}
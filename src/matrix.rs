@@ -0,0 +1,46 @@
+//! Generating tasks from a command template and lists of values to
+//! substitute into it.
+
+use crate::Task;
+
+/// Expand `template` once for every combination of the values of `vars`,
+/// replacing each `{name}` in it with the value of `name`.
+///
+/// Combinations are generated with the first variable varying slowest, and
+/// every task is named after its values, e.g. `linux-x86`. Braces that don't
+/// name one of `vars` are left alone, so shell syntax like `${HOME}` is safe.
+pub fn expand_matrix(template: &str, vars: &[(String, Vec<String>)]) -> Vec<Task> {
+    let mut combinations: Vec<Vec<(&str, &str)>> = vec![Vec::new()];
+    for (name, values) in vars {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((name.as_str(), value.as_str()));
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    combinations
+        .into_iter()
+        .map(|combination| {
+            let command = combination
+                .iter()
+                .fold(template.to_string(), |command, (name, value)| {
+                    command.replace(&format!("{{{name}}}"), value)
+                });
+            let name = combination
+                .iter()
+                .map(|(_, value)| *value)
+                .collect::<Vec<_>>()
+                .join("-");
+            Task {
+                name: (!name.is_empty()).then_some(name),
+                ..Task::from(command)
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,54 @@
+//! Where ptsd's own messages go: status lines, warnings about the run and
+//! problems with individual tasks.
+
+use indicatif::MultiProgress;
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// A destination for ptsd's own messages other than stderr, e.g. for
+/// capturing them (see [`RunOptions::messages`]).
+///
+/// [`RunOptions::messages`]: crate::RunOptions::messages
+#[derive(Clone)]
+pub struct MessageWriter(Arc<Mutex<dyn Write + Send>>);
+
+impl MessageWriter {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+}
+
+impl fmt::Debug for MessageWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageWriter").finish_non_exhaustive()
+    }
+}
+
+/// Prints messages to the chosen writer, or to stderr without garbling the
+/// progress bars, if there are any being drawn.
+#[derive(Clone)]
+pub(crate) struct Messages {
+    pub writer: Option<MessageWriter>,
+    pub progress_bars: Option<MultiProgress>,
+}
+
+impl Messages {
+    pub fn line(&self, line: impl fmt::Display) {
+        match (&self.writer, &self.progress_bars) {
+            (Some(MessageWriter(writer)), _) => {
+                // There's no one to report a failing message writer to.
+                let _ = writeln!(writer.lock().unwrap(), "{line}");
+            }
+            // The progress bars print nothing at all when they're hidden,
+            // e.g. when stderr isn't a terminal.
+            (None, Some(progress_bars)) if !progress_bars.is_hidden() => {
+                // There's no one to report a broken stderr to.
+                let _ = progress_bars.println(line.to_string());
+            }
+            (None, _) => {
+                let _ = writeln!(std::io::stderr(), "{line}");
+            }
+        }
+    }
+}
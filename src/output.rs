@@ -0,0 +1,932 @@
+//! Plumbing for tasks whose output is piped through ptsd, rather than written
+//! by the child straight into its log files.
+
+use crate::alarm::Alarm;
+use crate::combined::{CombinedLog, CombinedLogTee};
+use crate::events::{LineEvents, TaskEvent};
+use crate::Task;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdout};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+
+/// Destination of one of a task's output streams.
+pub trait LogSink: Write + Send {
+    /// Called once the task has exited and its stream was fully drained.
+    fn finish(self: Box<Self>, success: bool) -> io::Result<()>;
+}
+
+impl LogSink for File {
+    fn finish(mut self: Box<Self>, _success: bool) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Output that isn't logged anywhere.
+impl LogSink for io::Sink {
+    fn finish(self: Box<Self>, _success: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One of the output streams of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Creates the destinations of the output of tasks, for routing it somewhere
+/// other than the log directory (see [`RunOptions::output_sink`]).
+///
+/// [`RunOptions::output_sink`]: crate::RunOptions::output_sink
+pub trait OutputSink: std::fmt::Debug + Send + Sync {
+    /// Create the destination of `stream` of `task`. `task_name` is the
+    /// task's index, zero-padded the way log files are named.
+    fn open(&self, task_name: &str, task: &Task, stream: Stream) -> io::Result<Box<dyn LogSink>>;
+}
+
+/// Writes the output of every task to `<task_name>.stdout` and
+/// `<task_name>.stderr` in a directory, the way ptsd does by default.
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    pub dir: PathBuf,
+}
+
+impl OutputSink for FileSink {
+    fn open(&self, task_name: &str, _task: &Task, stream: Stream) -> io::Result<Box<dyn LogSink>> {
+        Ok(Box::new(File::create(log_path(
+            &self.dir, task_name, stream,
+        ))?))
+    }
+}
+
+/// Open a log file for writing, either replacing or appending to what it
+/// holds.
+pub(crate) fn open_log(path: &Path, append: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+}
+
+fn log_path(log_dir: &Path, task_name: &str, stream: Stream) -> PathBuf {
+    let (stdout, stderr) = crate::log_paths(log_dir, task_name);
+    match stream {
+        Stream::Stdout => stdout,
+        Stream::Stderr => stderr,
+    }
+}
+
+/// A cap on the output held in memory by all tasks together, as a semaphore
+/// of bytes.
+///
+/// Pumps take their share before reading a chunk, and give it back once the
+/// chunk is written out. While the budget is used up they stop reading,
+/// which leaves the tasks blocked on writing to their pipes. Deferred logs
+/// count what they hold too, but spill to their files instead of waiting,
+/// as they only give their share back once their task is done.
+pub struct OutputBudget {
+    bytes: Semaphore,
+    max: usize,
+}
+
+impl OutputBudget {
+    /// A budget of `max` bytes, or of a single byte if `max` is 0.
+    pub fn new(max: usize) -> Self {
+        let max = max.clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            bytes: Semaphore::new(max),
+            max,
+        }
+    }
+
+    /// Wait for `bytes` to be available, and hold them until the permit is
+    /// dropped. Asking for more than the whole budget waits forever.
+    async fn acquire(&self, bytes: usize) -> SemaphorePermit<'_> {
+        let bytes = bytes.try_into().unwrap_or(u32::MAX);
+        // The semaphore is never closed.
+        self.bytes.acquire_many(bytes).await.unwrap()
+    }
+
+    /// Count `bytes` more as held, unless that would exceed the budget.
+    fn try_hold(&self, bytes: usize) -> bool {
+        let Ok(bytes) = u32::try_from(bytes) else {
+            return false;
+        };
+        match self.bytes.try_acquire_many(bytes) {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.bytes.add_permits(bytes);
+    }
+}
+
+/// A log that only touches the disk if it has to.
+///
+/// Output is held in memory while the task runs, and is written to `path`
+/// only if the task fails. Should the output outgrow `limit` bytes, or the
+/// shared `budget`, it is spilled to `path` right away, and the file is
+/// removed if the task succeeds.
+pub struct DeferredLog {
+    path: PathBuf,
+    limit: usize,
+    budget: Option<Arc<OutputBudget>>,
+    /// Append to the file rather than replace it, once it's written.
+    append: bool,
+    buffer: Vec<u8>,
+    file: Option<File>,
+}
+
+impl DeferredLog {
+    pub fn new(
+        path: PathBuf,
+        limit: usize,
+        budget: Option<Arc<OutputBudget>>,
+        append: bool,
+    ) -> Self {
+        Self {
+            path,
+            limit,
+            budget,
+            append,
+            buffer: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Give the memory held by the buffer back to the budget.
+    fn release_buffer(&mut self) -> Vec<u8> {
+        let buffer = std::mem::take(&mut self.buffer);
+        if let Some(budget) = &self.budget {
+            budget.release(buffer.len());
+        }
+        buffer
+    }
+}
+
+impl Write for DeferredLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none()
+            && (self.buffer.len() + buf.len() > self.limit
+                || self
+                    .budget
+                    .as_ref()
+                    .is_some_and(|budget| !budget.try_hold(buf.len())))
+        {
+            let mut file = open_log(&self.path, self.append)?;
+            file.write_all(&self.release_buffer())?;
+            self.file = Some(file);
+        }
+
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => self.buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl LogSink for DeferredLog {
+    fn finish(mut self: Box<Self>, success: bool) -> io::Result<()> {
+        let buffer = self.release_buffer();
+        match (self.file.take(), success) {
+            (Some(file), true) => {
+                drop(file);
+                std::fs::remove_file(&self.path)
+            }
+            (Some(mut file), false) => file.flush(),
+            (None, true) => Ok(()),
+            (None, false) => std::fs::write(&self.path, buffer),
+        }
+    }
+}
+
+impl Drop for DeferredLog {
+    /// Tasks that are killed never get their logs finished.
+    fn drop(&mut self) {
+        self.release_buffer();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    Csi,
+    /// An OSC/DCS-like string, terminated by BEL or ST.
+    String,
+    StringEscape,
+}
+
+/// Removes ANSI escape sequences (colors, cursor movement, window titles...)
+/// from the output before handing it to the inner sink.
+///
+/// Sequences may be split across writes, so the parser state is kept between
+/// calls.
+pub struct AnsiStripper<W> {
+    inner: W,
+    state: AnsiState,
+}
+
+impl<W> AnsiStripper<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            state: AnsiState::Ground,
+        }
+    }
+}
+
+impl<W: Write> Write for AnsiStripper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        const ESC: u8 = 0x1b;
+        const BEL: u8 = 0x07;
+
+        let mut stripped = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            self.state = match (self.state, byte) {
+                (AnsiState::Ground, ESC) => AnsiState::Escape,
+                (AnsiState::Ground, _) => {
+                    stripped.push(byte);
+                    AnsiState::Ground
+                }
+                (AnsiState::Escape, b'[') => AnsiState::Csi,
+                (AnsiState::Escape, b']' | b'P' | b'X' | b'^' | b'_') => AnsiState::String,
+                (AnsiState::Escape | AnsiState::EscapeIntermediate, 0x20..=0x2f) => {
+                    AnsiState::EscapeIntermediate
+                }
+                (AnsiState::Escape | AnsiState::EscapeIntermediate, _) => AnsiState::Ground,
+                (AnsiState::Csi, 0x40..=0x7e) => AnsiState::Ground,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                (AnsiState::String, BEL) => AnsiState::Ground,
+                (AnsiState::String, ESC) => AnsiState::StringEscape,
+                (AnsiState::String, _) => AnsiState::String,
+                (AnsiState::StringEscape, b'\\') => AnsiState::Ground,
+                (AnsiState::StringEscape, _) => AnsiState::String,
+            };
+        }
+        self.inner.write_all(&stripped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl LogSink for AnsiStripper<Box<dyn LogSink>> {
+    fn finish(self: Box<Self>, success: bool) -> io::Result<()> {
+        self.inner.finish(success)
+    }
+}
+
+/// How much of a line the sinks that split output into lines hold on to
+/// while waiting for its end, unless told otherwise. Longer lines are cut
+/// into pieces of this length, each handled as a line of its own, so that
+/// output without newlines doesn't pile up in memory.
+pub(crate) const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Splits output into lines for the sinks that handle it line by line,
+/// holding on to at most a set number of bytes of each.
+pub(crate) struct LineSplitter {
+    /// The start of a line whose end wasn't written yet.
+    partial: Vec<u8>,
+    /// The most bytes of a line to hold on to.
+    max: usize,
+    /// Whether to cut lines longer than `max` short, instead of splitting
+    /// them into pieces.
+    truncate: bool,
+    /// How many bytes were cut off the line in `partial` so far.
+    truncated: usize,
+}
+
+impl LineSplitter {
+    /// Split lines longer than [`MAX_LINE_LENGTH`] into pieces or, given a
+    /// `max_line_length`, cut them short at that length and mark them with
+    /// how much was cut off.
+    pub fn new(max_line_length: Option<NonZeroUsize>) -> Self {
+        Self {
+            partial: Vec::new(),
+            max: max_line_length.map_or(MAX_LINE_LENGTH, NonZeroUsize::get),
+            truncate: max_line_length.is_some(),
+            truncated: 0,
+        }
+    }
+
+    /// Pass every line `buf` completes to `on_line`, without its newline.
+    pub fn write(
+        &mut self,
+        buf: &[u8],
+        mut on_line: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+            if self.partial.is_empty() && end <= self.max {
+                on_line(&rest[..end])?;
+            } else {
+                self.hold(&rest[..end]);
+                on_line(&self.take_line())?;
+            }
+            rest = &rest[end + 1..];
+        }
+        self.hold(rest);
+        if !self.truncate {
+            while self.partial.len() >= self.max {
+                let piece: Vec<_> = self.partial.drain(..self.max).collect();
+                on_line(&piece)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The line that was left without its end once the output is over, if
+    /// there's one.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        (!self.partial.is_empty() || self.truncated > 0).then(|| self.take_line())
+    }
+
+    fn hold(&mut self, bytes: &[u8]) {
+        if !self.truncate {
+            self.partial.extend_from_slice(bytes);
+            return;
+        }
+        let kept = bytes.len().min(self.max - self.partial.len());
+        self.partial.extend_from_slice(&bytes[..kept]);
+        self.truncated += bytes.len() - kept;
+    }
+
+    fn take_line(&mut self) -> Vec<u8> {
+        let mut line = std::mem::take(&mut self.partial);
+        if self.truncated > 0 {
+            write!(line, " (truncated {} bytes)", self.truncated).unwrap();
+            self.truncated = 0;
+        }
+        line
+    }
+}
+
+/// Collapses runs of identical consecutive lines into a single
+/// `<line> (repeated N times)` before handing them to the inner sink.
+///
+/// A line is only written out once a different one follows it, or the
+/// stream ends. Long lines are handled as [`LineSplitter`] does.
+pub struct LineDeduplicator<W> {
+    inner: W,
+    /// The last complete line, without its newline, and how many times in a
+    /// row it was seen.
+    last: Option<(Vec<u8>, usize)>,
+    lines: LineSplitter,
+}
+
+impl<W: Write> LineDeduplicator<W> {
+    pub fn new(inner: W, max_line_length: Option<NonZeroUsize>) -> Self {
+        Self {
+            inner,
+            last: None,
+            lines: LineSplitter::new(max_line_length),
+        }
+    }
+
+    fn write_last(&mut self) -> io::Result<()> {
+        match self.last.take() {
+            Some((line, count)) => write_repeated(&mut self.inner, &line, count),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write a line seen `count` times in a row, with its newline.
+fn write_repeated(writer: &mut impl Write, line: &[u8], count: usize) -> io::Result<()> {
+    writer.write_all(line)?;
+    if count > 1 {
+        write!(writer, " (repeated {count} times)")?;
+    }
+    writer.write_all(b"\n")
+}
+
+impl<W: Write> Write for LineDeduplicator<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Self { inner, last, lines } = self;
+        lines.write(buf, |line| {
+            match last {
+                Some((last, count)) if *last == line => *count += 1,
+                _ => {
+                    if let Some((line, count)) = last.take() {
+                        write_repeated(inner, &line, count)?;
+                    }
+                    *last = Some((line.to_vec(), 1));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl LogSink for LineDeduplicator<Box<dyn LogSink>> {
+    fn finish(mut self: Box<Self>, success: bool) -> io::Result<()> {
+        self.write_last()?;
+        if let Some(partial) = self.lines.finish() {
+            self.inner.write_all(&partial)?;
+        }
+        self.inner.finish(success)
+    }
+}
+
+/// Raise `alarm` if `error` means the disk is full.
+pub(crate) fn check_disk_full(alarm: &Alarm, error: &io::Error) {
+    if error.kind() == io::ErrorKind::StorageFull {
+        alarm.raise();
+    }
+}
+
+/// Raises an alarm when writing to the wrapped sink fails for lack of
+/// space.
+pub struct DiskFullDetector<W> {
+    inner: W,
+    alarm: Arc<Alarm>,
+}
+
+impl<W: Write> Write for DiskFullDetector<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner
+            .write(buf)
+            .inspect_err(|e| check_disk_full(&self.alarm, e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner
+            .flush()
+            .inspect_err(|e| check_disk_full(&self.alarm, e))
+    }
+}
+
+impl LogSink for DiskFullDetector<Box<dyn LogSink>> {
+    fn finish(self: Box<Self>, success: bool) -> io::Result<()> {
+        let alarm = self.alarm;
+        self.inner
+            .finish(success)
+            .inspect_err(|e| check_disk_full(&alarm, e))
+    }
+}
+
+/// Read whatever `reader` has ready into `buf`, without waiting for more.
+async fn read_ready(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    std::future::poll_fn(|cx| {
+        let mut read_buf = ReadBuf::new(buf);
+        match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+            Poll::Ready(result) => Poll::Ready(result.map(|()| read_buf.filled().len())),
+            Poll::Pending => Poll::Ready(Ok(0)),
+        }
+    })
+    .await
+}
+
+/// Copy everything from `reader` into `sink`, handing the sink back once the
+/// stream is exhausted, along with the number of bytes read. With a
+/// `budget`, every chunk is read into memory only once there's room for it.
+async fn pump(
+    mut reader: impl AsyncRead + Unpin,
+    mut sink: Box<dyn LogSink>,
+    budget: Option<Arc<OutputBudget>>,
+) -> io::Result<(Box<dyn LogSink>, u64)> {
+    let mut buf = [0u8; 8192];
+    let mut total = 0;
+    loop {
+        let (read, permit) = match &budget {
+            Some(budget) => {
+                // The first byte is waited for before taking a share of the
+                // budget, so that a task that prints nothing for a while
+                // doesn't hold on to it.
+                if reader.read(&mut buf[..1]).await? == 0 {
+                    return Ok((sink, total));
+                }
+                let len = buf.len().min(budget.max);
+                let permit = budget.acquire(len).await;
+                (
+                    1 + read_ready(&mut reader, &mut buf[1..len]).await?,
+                    Some(permit),
+                )
+            }
+            None => (reader.read(&mut buf).await?, None),
+        };
+        if read == 0 {
+            return Ok((sink, total));
+        }
+        total += read as u64;
+        sink.write_all(&buf[..read])?;
+        drop(permit);
+    }
+}
+
+/// How the output of spawned tasks is routed into their log files.
+#[derive(Clone)]
+pub struct OutputOptions {
+    /// Hold output in memory (up to this many bytes per stream) and only
+    /// write it out if the task fails.
+    pub deferred_limit: Option<usize>,
+    /// The most output all tasks may hold in memory together.
+    pub budget: Option<Arc<OutputBudget>>,
+    /// Remove ANSI escape sequences from the output.
+    pub strip_ansi: bool,
+    /// Run the task under a pty, whose output is piped through ptsd.
+    pub pty: bool,
+    /// Watch the writes to the logs for the disk filling up.
+    pub disk_full: Option<Arc<Alarm>>,
+    /// Send the output somewhere other than the log directory.
+    pub custom_sink: Option<Arc<dyn OutputSink>>,
+    /// Send every line of output as an event, too.
+    pub events: Option<UnboundedSender<TaskEvent>>,
+    /// Pipe each stream through this shell command before logging it.
+    pub filter: Option<String>,
+    /// Collapse runs of identical consecutive lines.
+    pub dedupe: bool,
+    /// Log every line as a JSON object, into a single file for both streams.
+    pub json_lines: bool,
+    /// Pipe the output even when it isn't logged, so that it can be measured.
+    pub count_output: bool,
+    /// Append to the logs instead of replacing them, as a restarted task
+    /// does.
+    pub append: bool,
+    /// Copy every line to this log, too.
+    pub combined_log: Option<Arc<CombinedLog>>,
+    /// Cut the lines that are handled one by one short at this many bytes.
+    pub max_line_length: Option<NonZeroUsize>,
+}
+
+impl OutputOptions {
+    /// Whether the child's output has to go through ptsd, instead of being
+    /// written by the child directly to the log files.
+    pub fn needs_pipes(&self) -> bool {
+        self.deferred_limit.is_some()
+            || self.strip_ansi
+            || self.pty
+            || self.disk_full.is_some()
+            || self.custom_sink.is_some()
+            || self.events.is_some()
+            || self.filter.is_some()
+            || self.dedupe
+            || self.json_lines
+            || self.count_output
+            || self.combined_log.is_some()
+    }
+
+    /// The sink for one stream of a task. Without a log directory, output
+    /// not going to a custom sink is discarded.
+    pub fn sink(
+        &self,
+        log_dir: Option<&Path>,
+        index: usize,
+        task_name: &str,
+        task: &Task,
+        stream: Stream,
+    ) -> io::Result<Box<dyn LogSink>> {
+        let mut sink = match (&self.custom_sink, log_dir, self.deferred_limit) {
+            // Custom sinks learn whether the task succeeded when they're
+            // finished, and can decide what to keep on their own.
+            (Some(custom_sink), _, _) => custom_sink.open(task_name, task, stream)?,
+            (None, None, _) => Box::new(io::sink()),
+            (None, Some(log_dir), _) if self.json_lines => Box::new(JsonLinesLog::open(
+                &crate::json_log_path(log_dir, task_name),
+                index,
+                stream,
+                self.append,
+                self.max_line_length,
+            )?),
+            (None, Some(log_dir), Some(limit)) => Box::new(DeferredLog::new(
+                log_path(log_dir, task_name, stream),
+                limit,
+                self.budget.clone(),
+                self.append,
+            )),
+            (None, Some(log_dir), None) if self.append => {
+                Box::new(open_log(&log_path(log_dir, task_name, stream), true)?)
+            }
+            (None, Some(log_dir), None) => FileSink {
+                dir: log_dir.to_path_buf(),
+            }
+            .open(task_name, task, stream)?,
+        };
+        // Underneath the stripping, so that the lines are sent as they are
+        // logged.
+        if let Some(events) = &self.events {
+            sink = Box::new(LineEvents::new(
+                sink,
+                index,
+                stream,
+                events.clone(),
+                self.max_line_length,
+            ));
+        }
+        if let Some(combined_log) = &self.combined_log {
+            sink = Box::new(CombinedLogTee::new(
+                sink,
+                index,
+                stream,
+                combined_log.clone(),
+                self.max_line_length,
+            ));
+        }
+        // Underneath the stripping too, so that lines differing only in the
+        // escape sequences it removes are collapsed.
+        if self.dedupe {
+            sink = Box::new(LineDeduplicator::new(sink, self.max_line_length));
+        }
+        if self.strip_ansi {
+            sink = Box::new(AnsiStripper::new(sink));
+        }
+        if let Some(alarm) = &self.disk_full {
+            sink = Box::new(DiskFullDetector {
+                inner: sink,
+                alarm: alarm.clone(),
+            });
+        }
+        Ok(sink)
+    }
+}
+
+/// Copy everything from `reader` into `writer`. Should `writer` stop
+/// accepting input, the rest of `reader` is discarded, rather than left for
+/// the writing side to block on.
+async fn feed(mut reader: impl AsyncRead + Unpin, writer: impl AsyncWrite + Unpin) {
+    use tokio::io::AsyncWriteExt;
+
+    let mut writer = Some(writer);
+    let mut buf = [0u8; 8192];
+    loop {
+        let Ok(read) = reader.read(&mut buf).await else {
+            return;
+        };
+        if read == 0 {
+            return;
+        }
+        if let Some(stdin) = &mut writer {
+            if stdin.write_all(&buf[..read]).await.is_err() {
+                writer = None;
+            }
+        }
+    }
+}
+
+/// An external command a stream is piped through on its way to its sink.
+pub struct OutputFilter {
+    child: Child,
+    feeder: JoinHandle<()>,
+}
+
+impl OutputFilter {
+    /// Spawn `command` and feed it everything read from `reader`, returning
+    /// the filter's output. Whatever the filter prints to its stderr is
+    /// discarded.
+    pub fn start(
+        mut command: tokio::process::Command,
+        reader: impl AsyncRead + Unpin + Send + 'static,
+    ) -> io::Result<(Self, ChildStdout)> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let feeder = tokio::spawn(feed(reader, stdin));
+        Ok((Self { child, feeder }, stdout))
+    }
+
+    async fn wait(mut self) -> io::Result<ExitStatus> {
+        self.feeder.await.map_err(io::Error::other)?;
+        self.child.wait().await
+    }
+}
+
+type PumpHandle = JoinHandle<io::Result<(Box<dyn LogSink>, u64)>>;
+
+/// The in-flight copies of a task's piped stdout and stderr.
+pub struct OutputPumps {
+    stdout: PumpHandle,
+    stderr: PumpHandle,
+    filters: Vec<OutputFilter>,
+}
+
+impl OutputPumps {
+    /// Start copying the given streams into their sinks, within `budget`.
+    /// `filters` are the ones the streams are read from, if any.
+    pub fn start(
+        stdout: impl AsyncRead + Unpin + Send + 'static,
+        stdout_sink: Box<dyn LogSink>,
+        stderr: impl AsyncRead + Unpin + Send + 'static,
+        stderr_sink: Box<dyn LogSink>,
+        filters: Vec<OutputFilter>,
+        budget: Option<Arc<OutputBudget>>,
+    ) -> Self {
+        Self {
+            stdout: tokio::spawn(pump(stdout, stdout_sink, budget.clone())),
+            stderr: tokio::spawn(pump(stderr, stderr_sink, budget)),
+            filters,
+        }
+    }
+
+    /// Wait for both streams to be drained, and for their filters to exit.
+    pub async fn drain(self) -> io::Result<DrainedOutput> {
+        let (stdout_sink, stdout_len) = self.stdout.await.map_err(io::Error::other)??;
+        let (stderr_sink, stderr_len) = self.stderr.await.map_err(io::Error::other)??;
+        let mut filter_failure = None;
+        for filter in self.filters {
+            let status = filter.wait().await?;
+            if !status.success() && filter_failure.is_none() {
+                filter_failure = Some(status);
+            }
+        }
+        Ok(DrainedOutput {
+            sinks: [stdout_sink, stderr_sink],
+            stdout_len,
+            stderr_len,
+            filter_failure,
+        })
+    }
+}
+
+/// The output of a task whose streams were fully drained, but whose log
+/// files weren't finalized yet.
+pub struct DrainedOutput {
+    sinks: [Box<dyn LogSink>; 2],
+    /// How many bytes the task wrote to its stdout.
+    pub stdout_len: u64,
+    /// How many bytes the task wrote to its stderr.
+    pub stderr_len: u64,
+    /// How the first output filter that failed exited, if any did.
+    pub filter_failure: Option<ExitStatus>,
+}
+
+impl DrainedOutput {
+    pub fn finish(self, success: bool) -> io::Result<()> {
+        for sink in self.sinks {
+            sink.finish(success)?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs every line of a stream as a JSON object holding the task's index,
+/// the stream, when the line was read and the line itself.
+///
+/// Both streams of a task append to the same file; their lines are written
+/// whole, with a single write each, so they don't get mixed up. Long lines
+/// are handled as [`LineSplitter`] does.
+pub(crate) struct JsonLinesLog {
+    file: File,
+    index: usize,
+    stream: Stream,
+    lines: LineSplitter,
+}
+
+impl JsonLinesLog {
+    /// Open the log of `stream`. Unless appending, the stdout log, opened
+    /// first, truncates the file.
+    pub fn open(
+        path: &Path,
+        index: usize,
+        stream: Stream,
+        append: bool,
+        max_line_length: Option<NonZeroUsize>,
+    ) -> io::Result<Self> {
+        if stream == Stream::Stdout && !append {
+            File::create(path)?;
+        }
+        Ok(Self {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            index,
+            stream,
+            lines: LineSplitter::new(max_line_length),
+        })
+    }
+
+    fn write_line(file: &mut File, index: usize, stream: Stream, line: &[u8]) -> io::Result<()> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let stream = match stream {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+        let mut record = serde_json::json!({
+            "index": index,
+            "stream": stream,
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            "line": String::from_utf8_lossy(line),
+        })
+        .to_string();
+        record.push('\n');
+        file.write_all(record.as_bytes())
+    }
+}
+
+impl Write for JsonLinesLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Self {
+            file,
+            index,
+            stream,
+            lines,
+        } = self;
+        lines.write(buf, |line| Self::write_line(file, *index, *stream, line))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl LogSink for JsonLinesLog {
+    fn finish(mut self: Box<Self>, _success: bool) -> io::Result<()> {
+        if let Some(partial) = self.lines.finish() {
+            Self::write_line(&mut self.file, self.index, self.stream, &partial)?;
+        }
+        self.flush()
+    }
+}
+
+/// Make the given log files end with exactly one newline, adding one or
+/// trimming those in excess. Empty and missing files are left alone.
+pub fn ensure_final_newlines(paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => ensure_final_newline(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn ensure_final_newline(mut file: File) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    // Where the trailing newlines start, found by reading backwards.
+    let mut content_end = len;
+    let mut buf = [0; 4096];
+    while content_end > 0 {
+        let chunk = content_end.min(buf.len() as u64);
+        let buf = &mut buf[..chunk as usize];
+        file.seek(SeekFrom::Start(content_end - chunk))?;
+        file.read_exact(buf)?;
+        match buf.iter().rposition(|&byte| byte != b'\n') {
+            Some(last) => {
+                content_end -= chunk - last as u64 - 1;
+                break;
+            }
+            None => content_end -= chunk,
+        }
+    }
+    match (len, content_end) {
+        (0, _) => Ok(()),
+        (len, content_end) if len == content_end => {
+            file.seek(SeekFrom::End(0))?;
+            file.write_all(b"\n")
+        }
+        // Output made of nothing but newlines is kept as a single empty line.
+        (_, content_end) => file.set_len(content_end + 1),
+    }
+}
+
+/// Make sure whatever exists of the given log files is on disk, not just in
+/// the OS's page cache. Missing files (e.g. discarded deferred logs) are
+/// skipped.
+pub fn sync_logs(paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        match File::open(path) {
+            Ok(file) => file.sync_all()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Holding the run at its first failure until the user is done looking
+//! around (see [`RunOptions::pause_on_failure`]).
+//!
+//! [`RunOptions::pause_on_failure`]: crate::RunOptions::pause_on_failure
+
+use crate::alarm::{self, Alarm};
+use crate::messages::Messages;
+use std::io::{BufRead, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{oneshot, Mutex};
+
+pub(crate) struct FailurePause {
+    /// Whether a task already failed.
+    used: AtomicBool,
+    /// Held for as long as the run is paused.
+    hold: Mutex<()>,
+}
+
+impl FailurePause {
+    /// A pause for interactive sessions; there's no one to press Enter
+    /// otherwise.
+    pub fn interactive() -> Option<Self> {
+        std::io::stdin().is_terminal().then(|| Self {
+            used: AtomicBool::new(false),
+            hold: Mutex::new(()),
+        })
+    }
+
+    /// Wait for the run to be resumed, if it is paused.
+    pub async fn wait_while_paused(&self) {
+        drop(self.hold.lock().await);
+    }
+
+    /// On the first failure of the run, print `details` and hold the run
+    /// until Enter is pressed, or the run is wound down by `drain`.
+    pub async fn pause_once(&self, messages: &Messages, details: &str, drain: &Option<Arc<Alarm>>) {
+        if self.used.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let _hold = self.hold.lock().await;
+        messages.line(details);
+        messages.line("Paused; no new tasks will be started. Press Enter to continue");
+        // A thread of its own rather than a blocking task, so that the
+        // runtime can shut down without anyone pressing Enter.
+        let (resume, resumed) = oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = std::io::stdin().lock().read_line(&mut String::new());
+            let _ = resume.send(());
+        });
+        tokio::select! {
+            _ = resumed => {}
+            () = alarm::wait_for(drain) => {}
+        }
+    }
+}
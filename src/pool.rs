@@ -0,0 +1,220 @@
+use crate::state::RunState;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The pool of job slots that tasks must hold a permit of while running.
+///
+/// Its capacity may change during the run (e.g. when ramping up or
+/// jittering), so it is tracked alongside the semaphore.
+pub(crate) struct JobPool {
+    permits: Arc<Semaphore>,
+    capacity: AtomicUsize,
+}
+
+impl JobPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(capacity)),
+            capacity: AtomicUsize::new(capacity),
+        }
+    }
+
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.permits.clone().acquire_owned().await.unwrap()
+    }
+
+    /// A single permit for `slots` slots, or for every slot if there are
+    /// fewer.
+    pub async fn acquire_many(&self, slots: usize) -> OwnedSemaphorePermit {
+        let slots = slots.min(self.capacity()).max(1);
+        self.permits
+            .clone()
+            .acquire_many_owned(slots as u32)
+            .await
+            .unwrap()
+    }
+
+    pub fn grow(&self, added: usize) {
+        self.capacity.fetch_add(added, Ordering::Relaxed);
+        self.permits.add_permits(added);
+    }
+
+    /// Take `removed` slots out of the pool, waiting for running tasks to
+    /// free them up if needed.
+    pub async fn shrink(&self, removed: usize) {
+        let Ok(permits) = self.permits.acquire_many(removed as u32).await else {
+            return;
+        };
+        permits.forget();
+        self.capacity.fetch_sub(removed, Ordering::Relaxed);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.capacity()
+            .saturating_sub(self.permits.available_permits())
+    }
+}
+
+/// Raise the capacity of `pool` by one slot at a time, evenly spreading the
+/// increments over `ramp`, until it reaches `jobs`. A ramp too short to
+/// spread the increments over raises it to `jobs` right away.
+pub(crate) async fn ramp_up(pool: Arc<JobPool>, jobs: usize, ramp: Duration) {
+    let steps = jobs.saturating_sub(pool.capacity());
+    if steps == 0 {
+        return;
+    }
+    let period = ramp / steps as u32;
+    if period.is_zero() {
+        pool.grow(steps);
+        return;
+    }
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+    for _ in 0..steps {
+        interval.tick().await;
+        pool.grow(1);
+    }
+}
+
+/// Every `interval`, move the capacity of `pool` to a random value between
+/// `min` and `max`, inclusive.
+///
+/// The pool is expected to start out at `max`, which is never exceeded.
+pub(crate) async fn jitter(pool: Arc<JobPool>, min: usize, max: usize, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let target = rand::thread_rng().gen_range(min..=max);
+        let capacity = pool.capacity();
+        if target > capacity {
+            pool.grow(target - capacity);
+        } else {
+            pool.shrink(capacity - target).await;
+        }
+    }
+}
+
+/// Spaces events out to an average `rate` a second, while letting up to a
+/// second's worth of them through at once after a quiet period.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        let burst = rate.ceil().max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Wait until the next event is allowed to happen.
+    pub async fn acquire(&mut self) {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last_refill = now;
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate)).await;
+        }
+    }
+}
+
+/// How long a task started by [`MemoryGate`] is assumed to take to allocate
+/// its memory, during which its reserve is still counted as taken.
+const MEMORY_SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// How often [`MemoryGate`] checks whether enough memory became available.
+const MEMORY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much memory the system can give to new processes without swapping,
+/// going by `MemAvailable` in `/proc/meminfo`.
+#[cfg(target_os = "linux")]
+pub(crate) fn available_memory() -> std::io::Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|kib| kib.trim().strip_suffix("kB"))
+        .and_then(|kib| kib.trim().parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "no MemAvailable in /proc/meminfo",
+            )
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn available_memory() -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "available memory can only be read on Linux",
+    ))
+}
+
+/// Holds tasks back until there's enough memory available for them, on top
+/// of the `jobs` limit (see [`RunOptions::reserve_memory_per_task`]).
+///
+/// Tasks that were let through less than [`MEMORY_SETTLE_TIME`] ago may not
+/// have allocated their memory yet, so their reserves count as taken too.
+///
+/// [`RunOptions::reserve_memory_per_task`]: crate::RunOptions::reserve_memory_per_task
+pub(crate) struct MemoryGate {
+    reserve: u64,
+    recently_started: VecDeque<Instant>,
+}
+
+impl MemoryGate {
+    pub fn new(reserve: u64) -> Self {
+        Self {
+            reserve,
+            recently_started: VecDeque::new(),
+        }
+    }
+
+    /// Wait until there's memory for one more task. A task is always let
+    /// through when none are running, so that the run doesn't get stuck on
+    /// a reserve the system never has available.
+    pub async fn acquire(&mut self, run_state: &RunState) {
+        loop {
+            let now = Instant::now();
+            while self
+                .recently_started
+                .front()
+                .is_some_and(|&started| now.duration_since(started) >= MEMORY_SETTLE_TIME)
+            {
+                self.recently_started.pop_front();
+            }
+            let needed = self.reserve * (self.recently_started.len() as u64 + 1);
+            match available_memory() {
+                Ok(available) if available < needed && run_state.counts().running > 0 => {
+                    tokio::time::sleep(MEMORY_POLL_INTERVAL).await;
+                }
+                // Not holding tasks back if the memory can no longer be read.
+                _ => break,
+            }
+        }
+        self.recently_started.push_back(Instant::now());
+    }
+}
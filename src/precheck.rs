@@ -0,0 +1,181 @@
+use crate::guard::AbortOnDrop;
+use crate::{SpawnOptions, Task};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Ask the shell to parse `cmd` without executing it.
+/// Returns the shell's complaint if the command is malformed.
+async fn check_command_syntax(shell: String, cmd: String) -> Result<(), String> {
+    let output = tokio::process::Command::new(&shell)
+        .arg("-n")
+        .arg("-c")
+        .arg(&cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("failed running {shell:?}: {e}"))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Run the readiness probe `probe` with `shell` (see
+/// [`RunOptions::precheck`](crate::RunOptions::precheck)). Returns its exit
+/// code and output if it failed, or why it couldn't be run.
+pub(crate) async fn run_probe(shell: &str, probe: &str) -> Result<(), (Option<i32>, String)> {
+    let output = tokio::process::Command::new(shell)
+        .arg("-c")
+        .arg(probe)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| (None, format!("failed running {shell:?}: {e}")))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut printed = String::from_utf8_lossy(&output.stdout).into_owned();
+    printed += &String::from_utf8_lossy(&output.stderr);
+    Err((output.status.code(), printed.trim_end().to_string()))
+}
+
+/// Why `cwd` can't be run in, if it can't.
+async fn check_cwd(cwd: &Path) -> Result<(), String> {
+    match tokio::fs::metadata(cwd).await {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(format!("the working directory {cwd:?} isn't a directory")),
+        Err(e) => Err(format!("the working directory {cwd:?} can't be used: {e}")),
+    }
+}
+
+/// Check that the working directories of the given tasks, each paired with
+/// its index, exist, and syntax-check their commands, at most `jobs` at a
+/// time.
+/// Tasks with their own interpreter aren't syntax-checked, as `-n` is a
+/// shell-ism.
+/// Returns the index and complaint of every problem found, by index.
+pub(crate) async fn prelaunch_check(
+    shell: &str,
+    tasks: &[(usize, &Task)],
+    jobs: usize,
+) -> Vec<(usize, String)> {
+    let mut problems = Vec::new();
+    for &(i, task) in tasks {
+        if let Some(cwd) = &task.cwd {
+            if let Err(problem) = check_cwd(cwd).await {
+                problems.push((i, problem));
+            }
+        }
+    }
+
+    let concurrent_checks = Arc::new(Semaphore::new(jobs));
+    let mut checks = Vec::new();
+    for &(i, task) in tasks {
+        if task.shell.is_some() {
+            continue;
+        }
+        let permit = concurrent_checks.clone().acquire_owned().await.unwrap();
+        let check = check_command_syntax(shell.to_string(), task.command.clone());
+        checks.push((
+            i,
+            AbortOnDrop::spawn(async move {
+                let res = check.await;
+                drop(permit);
+                res
+            }),
+        ));
+    }
+
+    for (i, check) in checks {
+        match check.await {
+            Ok(Ok(())) => {}
+            Ok(Err(problem)) => problems.push((i, problem)),
+            Err(join_err) => problems.push((i, format!("check failed: {join_err:?}"))),
+        }
+    }
+    // Stable, so the problems of a task stay in the order they were found.
+    problems.sort_by_key(|&(i, _)| i);
+    problems
+}
+
+/// Run the [`Task::only_if`] preconditions of the given tasks, each paired
+/// with its index, at most `jobs` at a time. Maps the index of each of those tasks to whether
+/// its precondition succeeded, or why it couldn't be run.
+pub(crate) async fn check_preconditions(
+    tasks: &[(usize, &Task)],
+    spawn: &SpawnOptions,
+    jobs: usize,
+) -> HashMap<usize, Result<bool, String>> {
+    let concurrent_checks = Arc::new(Semaphore::new(jobs));
+    let mut checks = Vec::new();
+    for &(i, task) in tasks {
+        let Some(only_if) = &task.only_if else {
+            continue;
+        };
+        let permit = concurrent_checks.clone().acquire_owned().await.unwrap();
+        let mut command =
+            crate::shell_command(&spawn.shell, only_if, task, spawn, spawn.own_process_group);
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        checks.push((
+            i,
+            AbortOnDrop::spawn(async move {
+                let status = command.status().await;
+                drop(permit);
+                status
+            }),
+        ));
+    }
+
+    let mut results = HashMap::new();
+    for (i, check) in checks {
+        let result = match check.await {
+            Ok(Ok(status)) => Ok(status.success()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(join_err) => Err(format!("check failed: {join_err:?}")),
+        };
+        results.insert(i, result);
+    }
+    results
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Make sure `shell` names an executable file, looking it up in `$PATH` the
+/// way spawning it would if it's a bare name.
+pub(crate) fn check_shell_exists(shell: &str) -> Result<(), String> {
+    if shell.contains(std::path::MAIN_SEPARATOR) || shell.contains('/') {
+        return if is_executable(Path::new(shell)) {
+            Ok(())
+        } else {
+            Err("no such executable file".to_string())
+        };
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    if std::env::split_paths(&path)
+        .map(|dir| dir.join(shell))
+        .any(|candidate| is_executable(&candidate))
+    {
+        Ok(())
+    } else {
+        Err("not found in $PATH".to_string())
+    }
+}
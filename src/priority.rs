@@ -0,0 +1,116 @@
+//! Running the commands at a lower CPU or I/O priority than ptsd itself (see
+//! [`RunOptions::nice`] and [`RunOptions::io_priority`]).
+//!
+//! [`RunOptions::nice`]: crate::RunOptions::nice
+//! [`RunOptions::io_priority`]: crate::RunOptions::io_priority
+
+use std::str::FromStr;
+
+/// The I/O scheduling classes of Linux, as in `ionice(1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    /// Only get disk time when no one else needs it. Takes no level.
+    Idle,
+}
+
+/// An I/O scheduling class and a level within it, from 0 (highest) to 7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPriority {
+    pub class: IoClass,
+    pub level: u8,
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    /// Parse `CLASS[:LEVEL]`, where the class is `realtime`, `best-effort`
+    /// or `idle`, or their `ionice` numbers 1 to 3. The level defaults to 4.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        let class = match class {
+            "realtime" | "1" => IoClass::Realtime,
+            "best-effort" | "2" => IoClass::BestEffort,
+            "idle" | "3" => IoClass::Idle,
+            _ => {
+                return Err(format!(
+                    "unknown I/O class {class:?}, expected realtime, best-effort or idle"
+                ))
+            }
+        };
+        let level = match (class, level) {
+            (IoClass::Idle, Some(_)) => return Err("the idle class takes no level".to_string()),
+            (IoClass::Idle, None) => 0,
+            (_, None) => 4,
+            (_, Some(level)) => match level.parse() {
+                Ok(level) if level <= 7 => level,
+                _ => return Err(format!("expected a level from 0 to 7, got {level:?}")),
+            },
+        };
+        Ok(Self { class, level })
+    }
+}
+
+/// Make `command` set its niceness and I/O priority before it starts.
+#[cfg(unix)]
+pub(crate) fn set_priority(
+    command: &mut tokio::process::Command,
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+) {
+    if nice.is_none() && io_priority.is_none() {
+        return;
+    }
+    // SAFETY: `setpriority` and `ioprio_set` are plain syscalls, and only
+    // affect the child.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(io_priority) = io_priority {
+                set_io_priority(io_priority)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(io_priority: IoPriority) -> std::io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let class = match io_priority.class {
+        IoClass::Realtime => 1,
+        IoClass::BestEffort => 2,
+        IoClass::Idle => 3,
+    };
+    let ioprio = (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(io_priority.level);
+    // SAFETY: `ioprio_set` takes no pointers.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_io_priority(_io_priority: IoPriority) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "I/O priorities are only supported on Linux",
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_priority(
+    _command: &mut tokio::process::Command,
+    _nice: Option<i32>,
+    _io_priority: Option<IoPriority>,
+) {
+}
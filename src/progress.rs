@@ -0,0 +1,285 @@
+use crate::{ProgressTarget, Task, TaskReport};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+
+const PROGRESS_TICK_FRAMES: &[&str] = &[
+    "( ●    )",
+    "(  ●   )",
+    "(   ●  )",
+    "(    ● )",
+    "(     ●)",
+    "(    ● )",
+    "(   ●  )",
+    "(  ●   )",
+    "( ●    )",
+    "(●     )",
+];
+
+/// What the spinner next to each task shows while it runs, and once it's
+/// done or has failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressGlyphs {
+    /// Cycled through while the task runs. Must not be empty.
+    pub spinner_frames: Vec<String>,
+    pub done: String,
+    pub fail: String,
+}
+
+impl Default for ProgressGlyphs {
+    fn default() -> Self {
+        Self {
+            spinner_frames: PROGRESS_TICK_FRAMES[..PROGRESS_TICK_FRAMES.len() - 1]
+                .iter()
+                .map(|frame| frame.to_string())
+                .collect(),
+            done: "🎉".to_string(),
+            fail: "❌".to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ProgressStylesByState {
+    pub progress: ProgressStyle,
+    pub done: ProgressStyle,
+    pub fail: ProgressStyle,
+}
+
+pub(crate) fn init_progress_styles(glyphs: &ProgressGlyphs) -> ProgressStylesByState {
+    // indicatif keeps the last frame for finished bars, and only cycles
+    // through the others.
+    let mut frames: Vec<&str> = glyphs.spinner_frames.iter().map(String::as_str).collect();
+    frames.push(frames.first().copied().unwrap_or_default());
+    let progress =
+        ProgressStyle::with_template("[{elapsed_precise}] #{prefix} {spinner:8.cyan} {msg:.cyan}")
+            .unwrap()
+            .tick_strings(&frames);
+
+    let done = ProgressStyle::with_template(
+        "[{elapsed_precise}] #{prefix} {spinner:8.green} {msg:.green}",
+    )
+    .unwrap()
+    .tick_strings(&[&glyphs.done]);
+
+    let fail =
+        ProgressStyle::with_template("[{elapsed_precise}] #{prefix} {spinner:8.red} {msg:.red}")
+            .unwrap()
+            .tick_strings(&[&glyphs.fail]);
+
+    ProgressStylesByState {
+        progress,
+        done,
+        fail,
+    }
+}
+
+/// How many of the last tasks to finish the compact progress display lists.
+const RECENT_TASKS_SHOWN: usize = 5;
+
+/// A single bar for the whole run, above the last few tasks to finish, in
+/// place of a spinner per task (see
+/// [`RunOptions::compact_progress`](crate::RunOptions::compact_progress)).
+pub(crate) struct CompactProgress {
+    total: ProgressBar,
+    failed: AtomicUsize,
+    /// The lines listing the last tasks to finish, oldest first, and their
+    /// text.
+    recent: Mutex<(Vec<ProgressBar>, VecDeque<String>)>,
+    multi_progress_bar: MultiProgress,
+    done: String,
+    fail: String,
+    max_message_width: Option<usize>,
+}
+
+impl CompactProgress {
+    pub fn new(
+        multi_progress_bar: &MultiProgress,
+        tasks: usize,
+        glyphs: &ProgressGlyphs,
+        tick_interval: Option<Duration>,
+        max_message_width: Option<usize>,
+    ) -> Self {
+        let total = multi_progress_bar.add(ProgressBar::new(tasks as u64));
+        total.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg:.red}",
+            )
+            .unwrap(),
+        );
+        if let Some(tick_interval) = tick_interval {
+            total.enable_steady_tick(tick_interval);
+        }
+        Self {
+            total,
+            failed: AtomicUsize::new(0),
+            recent: Mutex::new((Vec::new(), VecDeque::new())),
+            multi_progress_bar: multi_progress_bar.clone(),
+            done: glyphs.done.clone(),
+            fail: glyphs.fail.clone(),
+            max_message_width,
+        }
+    }
+
+    /// Count a task as done, and list it as the latest to finish.
+    pub fn task_done(&self, report: &TaskReport) {
+        let success = report.outcome.is_success();
+        let glyph = if success { &self.done } else { &self.fail };
+        let mut line = format!("{glyph} #{} {}", report.index, report.command);
+        if let Some(max_width) = self.max_message_width {
+            line = truncate_to_width(&line, max_width).into_owned();
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        let (bars, lines) = &mut *recent;
+        if lines.len() == RECENT_TASKS_SHOWN {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+        if bars.len() < lines.len() {
+            let bar = self.multi_progress_bar.add(ProgressBar::new_spinner());
+            bar.set_style(ProgressStyle::with_template("{msg}").unwrap());
+            bars.push(bar);
+        }
+        for (bar, line) in bars.iter().zip(lines.iter()) {
+            bar.set_message(line.clone());
+        }
+
+        if !success {
+            let failed = self.failed.fetch_add(1, Ordering::Relaxed) + 1;
+            self.total.set_message(format!("{failed} failed"));
+        }
+        self.total.inc(1);
+    }
+
+    /// Count one more task, one that was streamed in.
+    pub fn add_task(&self) {
+        self.total.inc_length(1);
+    }
+
+    /// Leave the display as it is once the run is over.
+    pub fn finish(&self) {
+        self.total.finish();
+        for bar in &self.recent.lock().unwrap().0 {
+            bar.finish();
+        }
+    }
+}
+
+/// How many rows the terminal the progress bars are drawn to has, unless
+/// they aren't drawn to one.
+#[cfg(unix)]
+pub(crate) fn terminal_rows(target: ProgressTarget) -> Option<usize> {
+    let fd = match target {
+        ProgressTarget::Stdout => libc::STDOUT_FILENO,
+        ProgressTarget::Stderr => libc::STDERR_FILENO,
+    };
+    terminal_size::terminal_size_using_fd(fd).map(|(_, terminal_size::Height(rows))| rows.into())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn terminal_rows(_target: ProgressTarget) -> Option<usize> {
+    terminal_size::terminal_size().map(|(_, terminal_size::Height(rows))| rows.into())
+}
+
+/// Fill in the `{index}`, `{name}` and `{cmd}` fields of `template` for
+/// the task at `index` (see [`RunOptions::message_template`]). Braces that
+/// aren't one of them are left alone.
+///
+/// [`RunOptions::message_template`]: crate::RunOptions::message_template
+pub(crate) fn render_message(template: &str, index: usize, task: &Task) -> String {
+    let mut message = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        message.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let value = match &rest[1..end] {
+            "index" => index.to_string(),
+            "name" => task.name.clone().unwrap_or_else(|| task.command.clone()),
+            "cmd" => task.command.clone(),
+            _ => {
+                message.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        message.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    message.push_str(rest);
+    message
+}
+
+/// Shorten `message` to at most `max_width` terminal columns, marking the cut
+/// with an ellipsis.
+///
+/// The message is cut on character boundaries, and wide characters (e.g.
+/// CJK) count for the two columns they take up.
+pub(crate) fn truncate_to_width(message: &str, max_width: usize) -> Cow<'_, str> {
+    const ELLIPSIS: char = '…';
+
+    let char_width = |c: char| c.width().unwrap_or(0);
+    if message.chars().map(char_width).sum::<usize>() <= max_width {
+        return Cow::Borrowed(message);
+    }
+
+    let budget = max_width.saturating_sub(char_width(ELLIPSIS));
+    let mut width = 0;
+    let mut truncated = String::new();
+    for c in message.chars() {
+        width += char_width(c);
+        if width > budget {
+            break;
+        }
+        truncated.push(c);
+    }
+    if max_width > 0 {
+        truncated.push(ELLIPSIS);
+    }
+    Cow::Owned(truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task() -> Task {
+        Task {
+            name: Some("build".to_string()),
+            ..Task::from("make all")
+        }
+    }
+
+    #[test]
+    fn render_message_fills_in_the_fields() {
+        assert_eq!(
+            render_message("{index} {name}: {cmd}", 3, &task()),
+            "3 build: make all"
+        );
+        // Tasks without a name go by their command.
+        let unnamed = Task::from("ls");
+        assert_eq!(render_message("[{name}]", 0, &unnamed), "[ls]");
+    }
+
+    #[test]
+    fn render_message_leaves_unknown_fields_alone() {
+        assert_eq!(
+            render_message("{nope} {} {{name}} {cmd", 1, &task()),
+            "{nope} {} {build} {cmd"
+        );
+    }
+
+    #[test]
+    fn render_message_keeps_an_unterminated_brace() {
+        assert_eq!(render_message("{index} {name", 1, &task()), "1 {name");
+        assert_eq!(render_message("ends in {", 1, &task()), "ends in {");
+        assert_eq!(render_message("{cmd}}", 1, &task()), "make all}");
+    }
+}
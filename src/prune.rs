@@ -0,0 +1,55 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+struct LogFile {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<LogFile>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if metadata.is_file() {
+            files.push(LogFile {
+                path: entry.path(),
+                modified: metadata.modified()?,
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Delete the oldest files under `log_dir` until its total size is at most
+/// `max_size` bytes. Files listed in `keep` (the logs of the current run) are
+/// never deleted, even if that leaves the directory over the limit.
+///
+/// Returns the number of bytes still used by the directory.
+pub(crate) fn prune_log_dir(
+    log_dir: &Path,
+    max_size: u64,
+    keep: &HashSet<PathBuf>,
+) -> io::Result<u64> {
+    let mut files = Vec::new();
+    collect_files(log_dir, &mut files)?;
+    files.sort_by_key(|file| file.modified);
+
+    let mut total: u64 = files.iter().map(|file| file.size).sum();
+    for file in files {
+        if total <= max_size {
+            break;
+        }
+        if keep.contains(&file.path) {
+            continue;
+        }
+        std::fs::remove_file(&file.path)?;
+        total -= file.size;
+    }
+    Ok(total)
+}
@@ -0,0 +1,114 @@
+//! Running tasks under a pseudo-terminal, for commands that behave
+//! differently when they're not attached to a TTY.
+
+use std::io;
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// A freshly allocated pseudo-terminal.
+pub(crate) struct Pty {
+    /// The side ptsd reads the task's output from.
+    pub master: OwnedFd,
+    /// The side handed to the task as its terminal.
+    pub slave: OwnedFd,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let size = libc::winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: `master` and `slave` are valid out-parameters, and a null
+        // name/termios are allowed by `openpty`.
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &size,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `openpty` succeeded, so both are open descriptors we own.
+        let pty = unsafe {
+            Pty {
+                master: OwnedFd::from_raw_fd(master),
+                slave: OwnedFd::from_raw_fd(slave),
+            }
+        };
+        pty.disable_output_processing()?;
+        Ok(pty)
+    }
+
+    /// Stop the terminal from turning `\n` into `\r\n`, which would otherwise
+    /// end up in the log files.
+    fn disable_output_processing(&self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: `termios` is plain data, fully initialized by `tcgetattr`
+        // before being read.
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(self.slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            termios.c_oflag &= !libc::OPOST;
+            if libc::tcsetattr(self.slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Make the spawned child the leader of a new session, with its stdout (which
+/// must already be the pty's slave side) as the controlling terminal.
+///
+/// Meant to be called from a `pre_exec` hook.
+pub(crate) fn make_controlling_terminal() -> io::Result<()> {
+    // SAFETY: both calls are async-signal-safe and only affect the child.
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCSCTTY as _, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Reads the output of a task from the master side of its pty.
+///
+/// Once the task (and all of its children) exit, reading from the master
+/// fails with `EIO` rather than returning EOF; this maps it back to EOF.
+pub(crate) struct PtyReader(tokio::fs::File);
+
+impl PtyReader {
+    pub fn new(master: OwnedFd) -> Self {
+        PtyReader(tokio::fs::File::from_std(std::fs::File::from(master)))
+    }
+}
+
+impl AsyncRead for PtyReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if e.raw_os_error() == Some(libc::EIO) => Poll::Ready(Ok(())),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Capping the resources each command may use with `setrlimit(2)` (see
+//! [`RunOptions::resource_limits`]).
+//!
+//! [`RunOptions::resource_limits`]: crate::RunOptions::resource_limits
+
+/// Limits on the resources of each command, applied as both its soft and
+/// its hard limits (but see `cpu_seconds`). `None` leaves a limit as ptsd's
+/// own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// CPU time in seconds, after which the command gets `SIGXCPU`. The hard
+    /// limit is a second more, as Linux sends `SIGKILL` instead once a
+    /// command reaches it.
+    pub cpu_seconds: Option<u64>,
+    /// The largest file in bytes the command may write; writing past it
+    /// gets it `SIGXFSZ`.
+    pub file_size: Option<u64>,
+    /// The size of the command's address space in bytes, past which its
+    /// allocations fail.
+    pub address_space: Option<u64>,
+    /// How many files the command may have open at once.
+    pub open_files: Option<u64>,
+}
+
+/// Make `command` set `limits` on itself before it starts.
+#[cfg(unix)]
+pub(crate) fn set_limits(command: &mut tokio::process::Command, limits: ResourceLimits) {
+    if limits == ResourceLimits::default() {
+        return;
+    }
+    let same = |limit: Option<u64>| limit.map(|limit| (limit, limit));
+    let limits = [
+        (
+            libc::RLIMIT_CPU,
+            limits.cpu_seconds.map(|cpu| (cpu, cpu.saturating_add(1))),
+        ),
+        (libc::RLIMIT_FSIZE, same(limits.file_size)),
+        (libc::RLIMIT_AS, same(limits.address_space)),
+        (libc::RLIMIT_NOFILE, same(limits.open_files)),
+    ];
+    // SAFETY: `setrlimit` is a plain syscall, and only affects the child.
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in limits {
+                let Some((soft, hard)) = limit else {
+                    continue;
+                };
+                let limit = libc::rlimit {
+                    rlim_cur: soft as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+                if libc::setrlimit(resource, &limit) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_limits(_command: &mut tokio::process::Command, _limits: ResourceLimits) {}
+
+/// Which limit a command went over, going by the signal that killed it.
+#[cfg(unix)]
+pub(crate) fn exceeded_limit(signal: i32) -> Option<&'static str> {
+    match signal {
+        libc::SIGXCPU => Some("CPU time limit"),
+        libc::SIGXFSZ => Some("file size limit"),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn exceeded_limit(_signal: i32) -> Option<&'static str> {
+    None
+}
+
+/// Whether a command was likely killed by the OOM killer, going by how it
+/// exited: killed by `SIGKILL`, or with the 137 a shell exits with when a
+/// child of it was.
+#[cfg(unix)]
+pub(crate) fn looks_oom_killed(signal: Option<i32>, exit_code: Option<i32>) -> bool {
+    signal == Some(libc::SIGKILL) || exit_code == Some(128 + libc::SIGKILL)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn looks_oom_killed(_signal: Option<i32>, _exit_code: Option<i32>) -> bool {
+    false
+}
+
+/// The signal that killed the process, if one did.
+#[cfg(unix)]
+pub(crate) fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn exit_signal(_status: &std::process::ExitStatus) -> Option<i32> {
+    None
+}
@@ -0,0 +1,73 @@
+//! A tiny HTTP endpoint exposing the state of a run as JSON.
+//!
+//! This deliberately understands just enough HTTP/1.1 to answer `GET /`
+//! from a browser or `curl`; it is not a general purpose server.
+
+use crate::state::RunState;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The most we're willing to read of a request before giving up on it.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long to wait before accepting connections again once accepting one
+/// failed, e.g. for running out of file descriptors, which retrying right
+/// away won't fix.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Answer requests on `listener` until the task is aborted.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<RunState>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            continue;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            // The client going away mid-response is none of our business.
+            let _ = handle_connection(stream, &state).await;
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &RunState) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/")) => (
+            "200 OK",
+            serde_json::to_string(&state.snapshot()).expect("the snapshot is serializable"),
+        ),
+        (Some("GET"), _) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,91 @@
+//! Where the tasks of a run come from: the ones given up front, followed by
+//! the ones streamed in while the run is in progress (see
+//! [`RunOptions::streamed_tasks`]).
+//!
+//! [`RunOptions::streamed_tasks`]: crate::RunOptions::streamed_tasks
+
+use crate::Task;
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Tasks to run as they arrive, until every sender is dropped. Clones share
+/// the same stream.
+#[derive(Debug, Clone)]
+pub struct TaskStream {
+    receiver: Arc<Mutex<Receiver<Task>>>,
+    len: Option<usize>,
+}
+
+impl TaskStream {
+    /// A stream of exactly `len` tasks, which lets the run be planned as if
+    /// they were all given up front, e.g. to set `PTSD_TASK_TOTAL`.
+    pub fn with_len(receiver: Receiver<Task>, len: usize) -> Self {
+        Self {
+            len: Some(len),
+            ..receiver.into()
+        }
+    }
+
+    /// How many tasks the stream has, if that's known in advance.
+    pub(crate) fn len(&self) -> Option<usize> {
+        self.len
+    }
+}
+
+impl From<Receiver<Task>> for TaskStream {
+    fn from(receiver: Receiver<Task>) -> Self {
+        Self {
+            receiver: Arc::new(Mutex::new(receiver)),
+            len: None,
+        }
+    }
+}
+
+/// The tasks of a run in order, each paired with its index.
+pub(crate) struct TaskSource {
+    given: std::iter::Enumerate<std::vec::IntoIter<Task>>,
+    streamed: Option<OwnedMutexGuard<Receiver<Task>>>,
+    /// The index of the next streamed task.
+    next_index: usize,
+}
+
+impl TaskSource {
+    pub async fn new(given: Vec<Task>, streamed: Option<TaskStream>) -> Self {
+        let next_index = given.len();
+        let streamed = match streamed {
+            Some(stream) => Some(stream.receiver.lock_owned().await),
+            None => None,
+        };
+        Self {
+            given: given.into_iter().enumerate(),
+            streamed,
+            next_index,
+        }
+    }
+
+    /// The next task, waiting for one to be streamed in once the given ones
+    /// run out. Cancelling the wait loses no tasks.
+    pub async fn next(&mut self) -> Option<(usize, Task)> {
+        if let Some(task) = self.given.next() {
+            return Some(task);
+        }
+        let task = self.streamed.as_mut()?.recv().await?;
+        let index = self.next_index;
+        self.next_index += 1;
+        Some((index, task))
+    }
+
+    /// The tasks that are left without waiting for any more to be streamed
+    /// in.
+    pub fn remaining(mut self) -> impl Iterator<Item = (usize, Task)> {
+        let mut streamed = Vec::new();
+        if let Some(receiver) = &mut self.streamed {
+            while let Ok(task) = receiver.try_recv() {
+                streamed.push(task);
+            }
+        }
+        let next_index = self.next_index;
+        self.given.chain((next_index..).zip(streamed))
+    }
+}
@@ -0,0 +1,126 @@
+//! Live view of the state of every task in a run, shared between the spawn
+//! loop, the tasks themselves and anything reporting on the run's progress.
+
+use crate::Task;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TaskState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TaskSnapshot {
+    pub index: usize,
+    pub command: String,
+    pub state: TaskState,
+}
+
+/// A point-in-time copy of the state of the run.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RunSnapshot {
+    pub total: usize,
+    pub queued: usize,
+    pub running: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub tasks: Vec<TaskSnapshot>,
+}
+
+pub(crate) struct RunState {
+    /// Indexed by the tasks' indices; tasks that aren't part of the run (see
+    /// [`RunOptions::select`](crate::RunOptions::select)) have no entry.
+    tasks: Mutex<Vec<Option<TaskSnapshot>>>,
+}
+
+impl RunState {
+    pub fn new(tasks: &[Task], selected: impl Fn(usize) -> bool) -> Self {
+        let tasks = tasks
+            .iter()
+            .enumerate()
+            .map(|(index, task)| {
+                selected(index).then(|| TaskSnapshot {
+                    index,
+                    command: task.command.clone(),
+                    state: TaskState::Queued,
+                })
+            })
+            .collect();
+        Self {
+            tasks: Mutex::new(tasks),
+        }
+    }
+
+    /// Add a task that was streamed in, queued.
+    pub fn add(&self, index: usize, task: &Task) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.len() <= index {
+            tasks.resize(index + 1, None);
+        }
+        tasks[index] = Some(TaskSnapshot {
+            index,
+            command: task.command.clone(),
+            state: TaskState::Queued,
+        });
+    }
+
+    pub fn set(&self, index: usize, state: TaskState) {
+        if let Some(Some(task)) = self.tasks.lock().unwrap().get_mut(index) {
+            task.state = state;
+        }
+    }
+
+    /// Count the tasks in each state, without copying the per-task details.
+    pub fn counts(&self) -> RunSnapshot {
+        let tasks = self.tasks.lock().unwrap();
+        let tasks = || tasks.iter().flatten();
+        let count = |state| tasks().filter(|task| task.state == state).count();
+        RunSnapshot {
+            total: tasks().count(),
+            queued: count(TaskState::Queued),
+            running: count(TaskState::Running),
+            succeeded: count(TaskState::Succeeded),
+            failed: count(TaskState::Failed),
+            skipped: count(TaskState::Skipped),
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> RunSnapshot {
+        let mut snapshot = self.counts();
+        snapshot.tasks = self
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .cloned()
+            .collect();
+        snapshot
+    }
+
+    /// Replace the file at `path` with the current snapshot, as JSON.
+    ///
+    /// The snapshot is written to a temporary file next to `path` and then
+    /// renamed over it, so readers never see a partially written file.
+    pub fn write_snapshot(&self, path: &Path) -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut file, &self.snapshot())?;
+        file.write_all(b"\n")?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
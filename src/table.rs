@@ -0,0 +1,95 @@
+//! An aligned, one-row-per-task table summarizing a run.
+
+use crate::progress::truncate_to_width;
+use crate::{RunReport, TaskOutcome, TaskReport};
+use std::io::{self, Write};
+use unicode_width::UnicodeWidthStr;
+
+/// The widest the command column of the table gets.
+const MAX_COMMAND_WIDTH: usize = 50;
+
+/// How the rows of the summary table are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SummaryOrder {
+    /// By task index.
+    Index,
+    /// Longest-running tasks first.
+    Duration,
+}
+
+fn status(outcome: &TaskOutcome) -> String {
+    match outcome {
+        TaskOutcome::Succeeded => "ok".to_string(),
+        TaskOutcome::Failed { .. } => "failed".to_string(),
+        TaskOutcome::NoOutput => "no output".to_string(),
+        TaskOutcome::WroteToStderr => "wrote to stderr".to_string(),
+        TaskOutcome::SpawnFailed(_) => "spawn failed".to_string(),
+        TaskOutcome::Duplicate { of } => format!("duplicate of {of}"),
+        TaskOutcome::InternalError(_) => "internal error".to_string(),
+        TaskOutcome::Interrupted => "interrupted".to_string(),
+        TaskOutcome::FilterFailed { .. } => "filter failed".to_string(),
+        TaskOutcome::PreconditionUnmet => "skipped".to_string(),
+        TaskOutcome::TimedOut => "timed out".to_string(),
+    }
+}
+
+fn exit_code(outcome: &TaskOutcome) -> String {
+    match outcome {
+        TaskOutcome::Succeeded
+        | TaskOutcome::NoOutput
+        | TaskOutcome::WroteToStderr
+        | TaskOutcome::FilterFailed { .. } => "0".to_string(),
+        TaskOutcome::Failed {
+            exit_code: Some(code),
+        } => code.to_string(),
+        TaskOutcome::Failed { exit_code: None } => "signal".to_string(),
+        _ => "-".to_string(),
+    }
+}
+
+pub(crate) fn write_table(
+    report: &RunReport,
+    order: SummaryOrder,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut tasks: Vec<&TaskReport> = report.tasks.iter().collect();
+    if order == SummaryOrder::Duration {
+        tasks.sort_by_key(|task| std::cmp::Reverse(task.duration));
+    }
+
+    let header = ["#", "COMMAND", "STATUS", "EXIT", "DURATION"].map(str::to_string);
+    let rows: Vec<[String; 5]> = tasks
+        .into_iter()
+        .map(|task| {
+            [
+                task.index.to_string(),
+                truncate_to_width(&task.command, MAX_COMMAND_WIDTH).into_owned(),
+                status(&task.outcome),
+                exit_code(&task.outcome),
+                format!("{:.2}s", task.duration.as_secs_f64()),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|cell| cell.width());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(widths).enumerate() {
+            let padding = " ".repeat(width - cell.width());
+            match i {
+                // Numbers are right-aligned, the last column isn't padded.
+                0 | 3 => line += &format!("{padding}{cell}  "),
+                4 => line += &format!("{padding}{cell}"),
+                _ => line += &format!("{cell}{padding}  "),
+            }
+        }
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
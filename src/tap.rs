@@ -0,0 +1,103 @@
+//! Streaming the results of a run in the Test Anything Protocol format.
+//!
+//! Test points are numbered in the order the tasks finish, as TAP consumers
+//! expect them to be sequential. The task index is part of the diagnostics
+//! of failed tasks.
+
+use crate::{TaskOutcome, TaskReport};
+use std::io::Write;
+use std::sync::Mutex;
+
+pub(crate) struct TapWriter {
+    /// The number of the last test point written.
+    last: Mutex<usize>,
+    /// Whether the plan is written last, as the number of tasks wasn't
+    /// known at the start.
+    plan_last: bool,
+}
+
+/// Escape `#`, which would otherwise start a TAP directive.
+fn escape_description(command: &str) -> String {
+    command.replace('\\', "\\\\").replace('#', "\\#")
+}
+
+impl TapWriter {
+    /// Start the TAP stream by writing the plan for `total` tasks, or leave
+    /// the plan to [`TapWriter::finish`] if there's no telling how many there
+    /// are.
+    pub fn start(total: Option<usize>) -> Self {
+        println!("TAP version 13");
+        if let Some(total) = total {
+            println!("1..{total}");
+        }
+        Self {
+            last: Mutex::new(0),
+            plan_last: total.is_none(),
+        }
+    }
+
+    /// End the TAP stream, writing the plan if it wasn't written at the start.
+    pub fn finish(&self) {
+        if self.plan_last {
+            println!("1..{}", *self.last.lock().unwrap());
+        }
+    }
+
+    pub fn report(&self, report: &TaskReport) {
+        let TaskReport {
+            index,
+            command,
+            outcome,
+            duration,
+            ..
+        } = report;
+
+        // Holding the lock while printing keeps the numbering in output order.
+        let mut last = self.last.lock().unwrap();
+        *last += 1;
+
+        let status = if outcome.is_success() { "ok" } else { "not ok" };
+        let mut test_point = format!("{status} {} - {}", *last, escape_description(command));
+        match outcome {
+            TaskOutcome::Duplicate { of } => {
+                test_point += &format!(" # SKIP duplicate of task {of}");
+            }
+            TaskOutcome::PreconditionUnmet => test_point += " # SKIP precondition not met",
+            _ => {}
+        }
+        test_point += "\n";
+        if !outcome.is_success() {
+            test_point += "  ---\n";
+            test_point += &format!("  index: {index}\n");
+            match outcome {
+                TaskOutcome::Failed { exit_code } => {
+                    let exit_code = exit_code.map_or("~".to_string(), |code| code.to_string());
+                    test_point += &format!("  exit_code: {exit_code}\n");
+                }
+                TaskOutcome::FilterFailed { exit_code } => {
+                    let exit_code = exit_code.map_or("~".to_string(), |code| code.to_string());
+                    test_point += "  message: \"output filter failed\"\n";
+                    test_point += &format!("  filter_exit_code: {exit_code}\n");
+                }
+                TaskOutcome::NoOutput => test_point += "  message: \"no output\"\n",
+                TaskOutcome::WroteToStderr => test_point += "  message: \"wrote to stderr\"\n",
+                TaskOutcome::Interrupted => test_point += "  message: \"interrupted\"\n",
+                TaskOutcome::TimedOut => test_point += "  message: \"timed out\"\n",
+                TaskOutcome::SpawnFailed(error) | TaskOutcome::InternalError(error) => {
+                    // A JSON string is a valid YAML scalar.
+                    test_point += &format!("  message: {}\n", serde_json::json!(error));
+                }
+                TaskOutcome::Succeeded
+                | TaskOutcome::Duplicate { .. }
+                | TaskOutcome::PreconditionUnmet => {}
+            }
+            test_point += &format!("  duration_ms: {}\n", duration.as_millis());
+            test_point += "  ...\n";
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        // There's no one to report a broken stdout to.
+        let _ = stdout.write_all(test_point.as_bytes());
+        let _ = stdout.flush();
+    }
+}
@@ -0,0 +1,111 @@
+//! sed-like substitutions applied to the commands before they're run.
+
+use regex::{Regex, RegexBuilder};
+use std::str::FromStr;
+
+/// A substitution written the way `sed` takes it, `s/PATTERN/REPLACEMENT/FLAGS`.
+///
+/// Any character can follow the `s` as the delimiter, and is escaped with a
+/// backslash where it's meant literally. The pattern uses the syntax of the
+/// `regex` crate. In the replacement, `&` stands for the whole match and
+/// `\1` to `\9` for capture groups. The flags are `g`, to replace every match
+/// rather than the first one, and `i`, to match regardless of case.
+#[derive(Debug, Clone)]
+pub struct CommandTransform {
+    regex: Regex,
+    /// The replacement, in the syntax of [`Regex::replace`].
+    replacement: String,
+    global: bool,
+}
+
+impl CommandTransform {
+    pub fn apply(&self, command: &str) -> String {
+        let limit = if self.global { 0 } else { 1 };
+        self.regex
+            .replacen(command, limit, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Split `s` at the first `delimiter` not escaped with a backslash, dropping
+/// the backslashes of escaped delimiters.
+fn split_at_delimiter(s: &str, delimiter: char) -> Option<(String, &str)> {
+    let mut part = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, escaped)) if escaped == delimiter => part.push(escaped),
+                Some((_, escaped)) => {
+                    part.push('\\');
+                    part.push(escaped);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter => return Some((part, &s[i + c.len_utf8()..])),
+            c => part.push(c),
+        }
+    }
+    None
+}
+
+/// Translate a sed replacement to the syntax of [`Regex::replace`].
+fn translate_replacement(replacement: &str) -> String {
+    let mut translated = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => translated.push_str("${0}"),
+            '$' => translated.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => {
+                    translated.push_str("${");
+                    translated.push(digit);
+                    translated.push('}');
+                }
+                Some('n') => translated.push('\n'),
+                Some('t') => translated.push('\t'),
+                Some('$') => translated.push_str("$$"),
+                Some(escaped) => translated.push(escaped),
+                None => translated.push('\\'),
+            },
+            c => translated.push(c),
+        }
+    }
+    translated
+}
+
+impl FromStr for CommandTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let delimiter = s
+            .strip_prefix('s')
+            .and_then(|rest| rest.chars().next())
+            .filter(|&delimiter| delimiter != '\\' && !delimiter.is_alphanumeric())
+            .ok_or_else(|| format!("expected s/PATTERN/REPLACEMENT/, got {s:?}"))?;
+        let rest = &s[1 + delimiter.len_utf8()..];
+        let unterminated = || format!("unterminated substitution {s:?}");
+        let (pattern, rest) = split_at_delimiter(rest, delimiter).ok_or_else(unterminated)?;
+        let (replacement, flags) = split_at_delimiter(rest, delimiter).ok_or_else(unterminated)?;
+
+        let mut global = false;
+        let mut case_insensitive = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => case_insensitive = true,
+                _ => return Err(format!("unknown flag {flag:?} in {s:?}")),
+            }
+        }
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|e| format!("invalid pattern {pattern:?}: {e}"))?;
+        Ok(Self {
+            regex,
+            replacement: translate_replacement(&replacement),
+            global,
+        })
+    }
+}
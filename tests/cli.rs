@@ -0,0 +1,580 @@
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+/// The `ptsd` binary, run in `dir` without progress bars.
+fn ptsd(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ptsd"));
+    command.current_dir(dir).arg("--disable-progress");
+    command
+}
+
+fn run(command: &mut Command) -> Output {
+    let output = command.output().unwrap();
+    eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+/// Whether the process `pid` is still alive, as opposed to gone or a zombie.
+fn is_running(pid: &str) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|stat| {
+        !stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .trim_start()
+            .starts_with('Z')
+    })
+}
+
+#[test]
+fn timeout_kills_the_whole_task_on_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let start = Instant::now();
+    let output = run(ptsd(dir.path())
+        .args(["--timeout", "1s", "--log-json", "--log-dir", "logs"])
+        .arg("sleep 7 & echo $! > pid; wait"));
+    assert_eq!(output.status.code(), Some(1));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let pid = std::fs::read_to_string(dir.path().join("pid")).unwrap();
+    assert!(!is_running(&pid), "{pid} is still running");
+}
+
+/// Send SIGINT to `pid`, as Ctrl-C in its terminal would.
+fn interrupt(pid: u32) {
+    let status = Command::new("kill")
+        .args(["-INT", &pid.to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn second_interrupt_kills_the_whole_task() {
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("pid");
+    let start = Instant::now();
+    let mut child = ptsd(dir.path())
+        .args(["--log-dir", "logs"])
+        .arg("sleep 9 & echo $! > pid.tmp; mv pid.tmp pid; wait")
+        .spawn()
+        .unwrap();
+    while !pid_file.exists() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+
+    // The first interrupt lets the running task finish.
+    interrupt(child.id());
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(is_running(&pid));
+    assert!(child.try_wait().unwrap().is_none());
+
+    interrupt(child.id());
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(130));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // Nothing reaps the orphaned `sleep` right away.
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while is_running(&pid) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!is_running(&pid), "{pid} is still running");
+}
+
+#[test]
+fn messages_reach_stderr_when_it_isnt_a_terminal() {
+    let dir = tempfile::tempdir().unwrap();
+    // Progress bars are on, but hidden since stderr is a pipe here.
+    let output = run(Command::new(env!("CARGO_BIN_EXE_ptsd"))
+        .current_dir(dir.path())
+        .args(["--echo-failures", "--log-dir", "logs"])
+        .arg("echo oops; false"));
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Writing standard outputs to"), "{stderr}");
+    assert!(stderr.contains("oops"), "{stderr}");
+    assert!(
+        stderr.contains("The following tasks failed: [0]"),
+        "{stderr}"
+    );
+}
+
+/// Run `command` with `stdin` as its standard input.
+fn run_with_stdin(command: &mut Command, stdin: &str) -> Output {
+    use std::io::Write;
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+#[test]
+fn huge_selection_of_streamed_tasks() {
+    let dir = tempfile::tempdir().unwrap();
+    let start = Instant::now();
+    let output = run_with_stdin(
+        ptsd(dir.path()).args([
+            "--json-stdin",
+            "--select",
+            "1-4000000000",
+            "--log-dir",
+            "logs",
+        ]),
+        "{\"cmd\": \"echo 0\"}\n{\"cmd\": \"echo 1\"}\n{\"cmd\": \"echo 2\"}\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let logs = dir.path().join("logs");
+    assert!(!logs.join("0.stdout").exists());
+    assert_eq!(
+        std::fs::read_to_string(logs.join("2.stdout")).unwrap(),
+        "2\n"
+    );
+}
+
+#[test]
+fn overlapping_selections_count_each_task_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(ptsd(dir.path())
+        .args(["--tap", "--select", "0-1,1-2,9", "--log-dir", "logs"])
+        .args(["true", "false", "true", "true"]));
+    assert_eq!(output.status.code(), Some(1));
+    let tap = String::from_utf8(output.stdout).unwrap();
+    assert!(tap.starts_with("TAP version 13\n1..3\n"), "{tap}");
+    assert!(tap.contains("\nnot ok 2 - false\n"), "{tap}");
+    assert!(tap.contains("\nok 3 - true\n"), "{tap}");
+}
+
+#[test]
+fn task_timeouts_override_the_global_one() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_with_stdin(
+        ptsd(dir.path()).args([
+            "--timeout",
+            "1s",
+            "--json-stdin",
+            "--summary-format",
+            "json",
+            "--log-dir",
+            "logs",
+        ]),
+        "{\"cmd\": \"sleep 2\", \"timeout\": \"10s\"}\n{\"cmd\": \"sleep 2\", \"name\": \"global\"}\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["succeeded_tasks"][0]["index"], 0);
+    assert_eq!(summary["failed_tasks"][0]["index"], 1);
+    assert_eq!(summary["failed_tasks"][0]["error"], "timed out");
+}
+
+#[test]
+fn retry_failed_reruns_failures_at_their_indices() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = run_with_stdin(
+        ptsd(dir.path()).args([
+            "--json-stdin",
+            "--summary-format",
+            "json",
+            "--log-dir",
+            "logs",
+        ]),
+        "{\"cmd\": \"true\"}\n\
+         {\"cmd\": \"echo one; exit 3\", \"name\": \"one\"}\n\
+         {\"cmd\": \"true\"}\n\
+         {\"cmd\": \"test -e fixed || exit 4\"}\n",
+    );
+    assert_eq!(first.status.code(), Some(1));
+    std::fs::write(dir.path().join("summary.json"), &first.stdout).unwrap();
+    std::fs::write(dir.path().join("fixed"), "").unwrap();
+
+    let retry = run(ptsd(dir.path()).args([
+        "--retry-failed",
+        "summary.json",
+        "--summary-format",
+        "json",
+        "--log-dir",
+        "retry",
+    ]));
+    assert_eq!(retry.status.code(), Some(1));
+    let summary: serde_json::Value = serde_json::from_slice(&retry.stdout).unwrap();
+    assert_eq!(summary["total"], 2);
+    assert_eq!(summary["failed_tasks"][0]["index"], 1);
+    assert_eq!(summary["failed_tasks"][0]["name"], "one");
+    assert_eq!(summary["succeeded_tasks"][0]["index"], 3);
+    let logs = dir.path().join("retry");
+    assert_eq!(
+        std::fs::read_to_string(logs.join("1-one.stdout")).unwrap(),
+        "one\n"
+    );
+    assert!(logs.join("3.stdout").exists());
+    assert!(!logs.join("0.stdout").exists());
+}
+
+#[test]
+fn json_summary_embeds_small_logs() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(ptsd(dir.path())
+        .args(["--summary-format", "json", "--log-dir", "logs"])
+        .args(["--report-json-embed", "--embed-max-bytes", "100"])
+        .args([
+            "echo out; echo err >&2",
+            "printf '\\377\\376'",
+            "head -c 500 /dev/zero; exit 1",
+        ]));
+    assert_eq!(output.status.code(), Some(1));
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let text = &summary["succeeded_tasks"][0]["logs"];
+    assert_eq!(text["stdout"]["text"], "out\n");
+    assert_eq!(text["stderr"]["text"], "err\n");
+    let binary = &summary["succeeded_tasks"][1]["logs"];
+    assert_eq!(binary["stdout"]["base64"], "//4=");
+    let large = &summary["failed_tasks"][0]["logs"];
+    assert_eq!(large["stdout"]["path"], "logs/2.stdout");
+    assert_eq!(large["stderr"]["text"], "");
+}
+
+#[test]
+fn undefined_variables_in_command_files_fail_before_anything_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("commands"),
+        "touch ran\necho $PTSD_TEST_UNDEFINED\n",
+    )
+    .unwrap();
+    let output = run(ptsd(dir.path()).args([
+        "--expand-env",
+        "--command-file",
+        "commands",
+        "--log-dir",
+        "logs",
+    ]));
+    assert_eq!(output.status.code(), Some(9));
+    assert!(!dir.path().join("ran").exists());
+}
+
+#[test]
+fn undefined_variables_in_fifos_fail_once_they_are_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("commands");
+    assert!(Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+    let writer = std::thread::spawn(move || {
+        std::fs::write(fifo, "touch ran\necho $PTSD_TEST_UNDEFINED\n").unwrap();
+    });
+    let output = run(ptsd(dir.path()).args([
+        "--expand-env",
+        "-j",
+        "1",
+        "--command-file",
+        "commands",
+        "--log-dir",
+        "logs",
+    ]));
+    writer.join().unwrap();
+    assert_eq!(output.status.code(), Some(9));
+    // A FIFO can't be read ahead of time, so the commands before the
+    // undefined variable have run by the time it's found.
+    assert!(dir.path().join("ran").exists());
+}
+
+#[test]
+fn tap_reports_every_task_as_a_test_point() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(ptsd(dir.path())
+        .args(["--tap", "-j", "1", "--dedup", "--no-log"])
+        .args(["true", "exit 3", "true", "echo '#x'"]));
+    assert_eq!(output.status.code(), Some(1));
+    let tap = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = tap.lines().collect();
+    assert_eq!(lines[..2], ["TAP version 13", "1..4"]);
+    // Test points are numbered in the order the tasks finish.
+    let mut test_points: Vec<_> = lines
+        .iter()
+        .filter_map(|line| line.split_once(" - "))
+        .map(|(status, description)| (status.starts_with("ok"), description))
+        .collect();
+    test_points.sort_by_key(|&(_, description)| description);
+    assert_eq!(
+        test_points,
+        [
+            (true, "echo '\\#x'"),
+            (false, "exit 3"),
+            (true, "true"),
+            (true, "true # SKIP duplicate of task 0"),
+        ]
+    );
+    assert!(tap.contains("  ---\n  index: 1\n  exit_code: 3\n"), "{tap}");
+}
+
+#[test]
+fn tap_plan_comes_last_for_streamed_tasks() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_with_stdin(
+        ptsd(dir.path()).args(["--tap", "--no-log", "--json-stdin", "-j", "1"]),
+        "{\"cmd\": \"true\"}\n{\"cmd\": \"true\"}\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "TAP version 13\nok 1 - true\nok 2 - true\n1..2\n"
+    );
+}
+
+#[test]
+fn full_log_dir_falls_back_for_the_remaining_tasks() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("logs")).unwrap();
+    // A log directory with room for the directory itself and the two logs of
+    // the first task only, in a mount namespace of its own.
+    let script = format!(
+        "mount -t tmpfs -o nr_inodes=3 tmpfs logs || exit 77
+        {} --disable-progress --log-dir logs --log-dir-fallback fallback \"$@\"
+        status=$?
+        cat logs/0.stdout
+        exit $status",
+        env!("CARGO_BIN_EXE_ptsd")
+    );
+    let mut command = Command::new("unshare");
+    command
+        .current_dir(dir.path())
+        .args(["-rm", "sh", "-c", &script, "sh"])
+        .args(["echo first", "echo second", "echo third; false"]);
+    let output = match command.output() {
+        Ok(output) if output.status.code() != Some(77) => output,
+        // User namespaces aren't available here.
+        _ => return,
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("{stderr}");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "first\n");
+    assert!(stderr.contains("is full, writing the logs of task 1 on to \"fallback\""));
+    assert!(stderr.contains("or in \"fallback\" from task 1 on"));
+    let fallback = dir.path().join("fallback");
+    assert_eq!(
+        std::fs::read_to_string(fallback.join("1.stdout")).unwrap(),
+        "second\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(fallback.join("2.stdout")).unwrap(),
+        "third\n"
+    );
+}
+
+/// A server that accepts a single request, answers it with `status` and
+/// yields the request's head and body.
+fn webhook(status: &str) -> (String, std::thread::JoinHandle<(String, String)>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert_ne!(reader.read_line(&mut head).unwrap(), 0);
+        }
+        let length = head
+            .lines()
+            .find_map(|line| {
+                line.to_ascii_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|n| n.trim().parse().unwrap())
+            })
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (&stream).write_all(response.as_bytes()).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    });
+    (format!("http://{addr}/hook"), server)
+}
+
+#[test]
+fn summary_webhook_posts_the_json_summary() {
+    let dir = tempfile::tempdir().unwrap();
+    let (url, server) = webhook("200 OK");
+    let url = url.replace("http://", "http://user:secret@");
+    let output = run(ptsd(dir.path())
+        .args([
+            "--summary-webhook",
+            &url,
+            "--webhook-header",
+            "X-Run: nightly",
+        ])
+        .args(["true", "false"]));
+    assert_eq!(output.status.code(), Some(1));
+
+    let (head, body) = server.join().unwrap();
+    assert!(head.starts_with("POST /hook HTTP/1.1\r\n"), "{head}");
+    let head = head.to_ascii_lowercase();
+    // "user:secret", in basic auth rather than the URL.
+    assert!(
+        head.contains("authorization: basic dxnlcjpzzwnyzxq=\r\n"),
+        "{head}"
+    );
+    assert!(head.contains("x-run: nightly\r\n"), "{head}");
+    assert!(
+        head.contains("content-type: application/json\r\n"),
+        "{head}"
+    );
+    let summary: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(summary["succeeded_tasks"][0]["index"], 0);
+    assert_eq!(summary["failed_tasks"][0]["index"], 1);
+}
+
+#[test]
+fn strict_webhook_fails_the_run_when_the_post_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (url, server) = webhook("500 Internal Server Error");
+    let output =
+        run(ptsd(dir.path()).args(["--summary-webhook", &url, "--strict-webhook", "true"]));
+    server.join().unwrap();
+    assert_eq!(output.status.code(), Some(11));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Failed posting the summary to the --summary-webhook"));
+
+    // Without --strict-webhook it's only a warning.
+    let (url, server) = webhook("500 Internal Server Error");
+    let output = run(ptsd(dir.path()).args(["--summary-webhook", &url, "true"]));
+    server.join().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// A project with a `ptsd.toml` at its root, and a subdirectory to run in.
+fn configured_project() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("ptsd.toml"),
+        "log-dir = \"logs\"\ntimeout = \"200ms\"\ncommand-file = \"commands\"\n",
+    )
+    .unwrap();
+    std::fs::write(dir.path().join("commands"), "echo from the file\nsleep 2\n").unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    dir
+}
+
+#[test]
+fn config_file_fills_in_missing_options() {
+    let dir = configured_project();
+    let output = run(&mut ptsd(&dir.path().join("sub")));
+    // The second command outlasts the timeout of the config file.
+    assert_eq!(output.status.code(), Some(1));
+    // Paths in the config file are relative to it.
+    let logs = dir.path().join("logs");
+    assert_eq!(
+        std::fs::read_to_string(logs.join("0.stdout")).unwrap(),
+        "from the file\n"
+    );
+    assert!(logs.join("1.stdout").exists());
+}
+
+#[test]
+fn command_line_overrides_the_config_file() {
+    let dir = configured_project();
+    let sub = dir.path().join("sub");
+    let output = run(ptsd(&sub)
+        .args(["--log-dir", "own-logs", "--timeout", "10s"])
+        .arg("sleep 0.5; echo from the command line"));
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(sub.join("own-logs/0.stdout")).unwrap(),
+        "from the command line\n"
+    );
+    assert!(!dir.path().join("logs").exists());
+
+    let output = run(ptsd(&sub).args(["--no-config", "true"]));
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!dir.path().join("logs").exists());
+
+    std::fs::write(dir.path().join("ptsd.toml"), "retries = 3\n").unwrap();
+    let output = run(ptsd(&sub).arg("true"));
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid config file"));
+}
+
+/// Run `ptsd` with `args` in `dir`, in a terminal so that it draws its
+/// progress bars, and yield what it drew without the escape sequences.
+/// `None` if there's no `script` to provide the terminal.
+fn run_in_terminal(dir: &Path, args: &[&str]) -> Option<String> {
+    let quote = |arg: &str| format!("'{}'", arg.replace('\'', r"'\''"));
+    let command_line = std::iter::once(env!("CARGO_BIN_EXE_ptsd"))
+        .chain(args.iter().copied())
+        .map(quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let output = Command::new("script")
+        .current_dir(dir)
+        .args(["-qec", &command_line, "/dev/null"])
+        .output()
+        .ok()?;
+    let drawn = String::from_utf8_lossy(&output.stdout);
+    let escapes = regex::Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]").unwrap();
+    let drawn = escapes.replace_all(&drawn, "").into_owned();
+    eprintln!("{drawn}");
+    Some(drawn)
+}
+
+#[test]
+fn message_template_replaces_the_commands_next_to_the_bars() {
+    let dir = tempfile::tempdir().unwrap();
+    let Some(drawn) = run_in_terminal(
+        dir.path(),
+        &[
+            "--log-dir",
+            "logs",
+            "--progress-message-template",
+            "task {index} runs [{cmd}] {missing} {",
+            "sleep 0.2",
+        ],
+    ) else {
+        return;
+    };
+    assert!(
+        drawn.contains("task 0 runs [sleep 0.2] {missing} {"),
+        "{drawn}"
+    );
+}
+
+#[test]
+fn compact_progress_sums_up_the_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let Some(drawn) = run_in_terminal(
+        dir.path(),
+        &[
+            "--log-dir",
+            "logs",
+            "--progress-summary-only",
+            "sleep 0.2; echo spinner",
+            "false",
+            "true",
+        ],
+    ) else {
+        return;
+    };
+    assert!(drawn.contains("3/3 1 failed"), "{drawn}");
+    for line in ["#0 sleep 0.2; echo spinner", "#1 false", "#2 true"] {
+        assert!(drawn.contains(line), "{line:?} missing from {drawn}");
+    }
+    // No spinner is drawn for the tasks themselves.
+    assert!(!drawn.contains("] #0 ("), "{drawn}");
+    assert!(!drawn.contains('●'), "{drawn}");
+}
@@ -0,0 +1,35 @@
+use ptsd::{expand_env, SetupError};
+
+fn lookup(name: &str) -> Option<String> {
+    match name {
+        "HOME" => Some("/home/me".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    }
+}
+
+#[test]
+fn substitutes_plain_and_braced_variables() {
+    assert_eq!(
+        expand_env("ls $HOME/a ${HOME}b x${EMPTY}y", false, lookup).unwrap(),
+        "ls /home/me/a /home/meb xy"
+    );
+}
+
+#[test]
+fn leaves_other_dollar_syntax_alone() {
+    let command = "echo $1 $? $$ $(date) ${HOME:-x} ${ trailing $";
+    assert_eq!(expand_env(command, false, lookup).unwrap(), command);
+}
+
+#[test]
+fn undefined_variables_are_an_error_unless_allowed() {
+    match expand_env("echo $NOPE", false, lookup) {
+        Err(SetupError::UndefinedVariable { command, name }) => {
+            assert_eq!(command, "echo $NOPE");
+            assert_eq!(name, "NOPE");
+        }
+        other => panic!("unexpected result: {other:?}"),
+    }
+    assert_eq!(expand_env("echo $NOPE.", true, lookup).unwrap(), "echo .");
+}
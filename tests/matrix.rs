@@ -0,0 +1,37 @@
+use ptsd::expand_matrix;
+
+#[test]
+fn expands_the_cartesian_product_in_order() {
+    let vars = [
+        (
+            "os".to_string(),
+            vec!["linux".to_string(), "mac".to_string()],
+        ),
+        (
+            "arch".to_string(),
+            vec!["x86".to_string(), "arm".to_string()],
+        ),
+    ];
+    let tasks = expand_matrix("build --os={os} --arch={arch} ${HOME}", &vars);
+
+    let commands: Vec<_> = tasks.iter().map(|task| task.command.as_str()).collect();
+    assert_eq!(
+        commands,
+        [
+            "build --os=linux --arch=x86 ${HOME}",
+            "build --os=linux --arch=arm ${HOME}",
+            "build --os=mac --arch=x86 ${HOME}",
+            "build --os=mac --arch=arm ${HOME}",
+        ]
+    );
+    let names: Vec<_> = tasks.iter().map(|task| task.name.as_deref()).collect();
+    assert_eq!(
+        names,
+        [
+            Some("linux-x86"),
+            Some("linux-arm"),
+            Some("mac-x86"),
+            Some("mac-arm")
+        ]
+    );
+}
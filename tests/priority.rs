@@ -0,0 +1,24 @@
+use ptsd::{IoClass, IoPriority};
+
+#[test]
+fn parses_io_priorities_like_ionice() {
+    let parse = |s: &str| s.parse::<IoPriority>();
+    assert_eq!(
+        parse("best-effort:7"),
+        Ok(IoPriority {
+            class: IoClass::BestEffort,
+            level: 7
+        })
+    );
+    assert_eq!(
+        parse("1"),
+        Ok(IoPriority {
+            class: IoClass::Realtime,
+            level: 4
+        })
+    );
+    assert_eq!(parse("idle").unwrap().class, IoClass::Idle);
+    for bad in ["idle:0", "best-effort:8", "low", "2:"] {
+        assert!(parse(bad).is_err(), "{bad:?}");
+    }
+}
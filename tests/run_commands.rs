@@ -0,0 +1,1626 @@
+use ptsd::{
+    run_commands, run_commands_with_events, EmptyOutputPolicy, LogSink, MessageWriter, OutputSink,
+    RunOptions, RunReport, SetupError, Stream, SummaryOrder, Task, TaskEvent, TaskOutcome,
+    TaskStream,
+};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+async fn run_in(log_dir: &Path, commands: &[&str], options: RunOptions) -> RunReport {
+    run_commands(RunOptions {
+        tasks: commands.iter().map(|&command| command.into()).collect(),
+        log_dir: Some(log_dir.to_path_buf()),
+        disable_progress: true,
+        ..options
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn reports_failures_and_exit_codes() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["true", "false", "sleep 0.1", "exit 3"],
+        RunOptions::default(),
+    )
+    .await;
+
+    assert!(!report.success());
+    assert_eq!(report.failed_tasks(), vec![1, 3]);
+    assert!(report.tasks.iter().all(|task| task.pid.is_some()));
+    let outcomes: Vec<_> = report.tasks.iter().map(|task| &task.outcome).collect();
+    assert_eq!(
+        outcomes,
+        [
+            &TaskOutcome::Succeeded,
+            &TaskOutcome::Failed { exit_code: Some(1) },
+            &TaskOutcome::Succeeded,
+            &TaskOutcome::Failed { exit_code: Some(3) },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn writes_output_to_log_dir() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo hello", "echo oops >&2"],
+        RunOptions::default(),
+    )
+    .await;
+
+    assert!(report.success());
+    assert_eq!(report.log_dir.as_deref(), Some(log_dir.path()));
+    let read_log = |name: &str| std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+    assert_eq!(read_log("0.stdout"), "hello\n");
+    assert_eq!(read_log("0.stderr"), "");
+    assert_eq!(read_log("1.stdout"), "");
+    assert_eq!(read_log("1.stderr"), "oops\n");
+}
+
+#[tokio::test]
+async fn single_job_runs_serially() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let lock = log_dir.path().join("lock");
+    // Each command fails if another one holds the lock at the same time.
+    let cmd = format!("mkdir {lock:?} && sleep 0.05 && rmdir {lock:?}");
+    let report = run_in(
+        log_dir.path(),
+        &[&cmd, &cmd, &cmd, &cmd],
+        RunOptions {
+            jobs: NonZeroUsize::new(1).unwrap(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(report.success(), "{report:?}");
+}
+
+#[tokio::test]
+async fn missing_command_fails_through_the_shell() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["this-command-does-not-exist"],
+        RunOptions::default(),
+    )
+    .await;
+
+    assert_eq!(
+        report.tasks[0].outcome,
+        TaskOutcome::Failed {
+            exit_code: Some(127)
+        }
+    );
+}
+
+#[tokio::test]
+async fn missing_shell_is_a_spawn_failure() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["true", "true"],
+        RunOptions {
+            shell: "/this/shell/does/not/exist".to_string(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert_eq!(report.failed_tasks(), vec![0, 1]);
+    assert!(report
+        .tasks
+        .iter()
+        .all(|task| matches!(task.outcome, TaskOutcome::SpawnFailed(_))));
+}
+
+#[tokio::test]
+async fn silent_tasks_fail_on_empty_output() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["true", "echo hi", "echo hi >&2"],
+        RunOptions {
+            fail_on_empty_output: Some(EmptyOutputPolicy::Stdout),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(report.tasks[0].outcome, TaskOutcome::NoOutput);
+    assert_eq!(report.failed_tasks(), vec![0, 2]);
+
+    let report = run_in(
+        log_dir.path(),
+        &["true", "echo hi", "echo hi >&2"],
+        RunOptions {
+            fail_on_empty_output: Some(EmptyOutputPolicy::Any),
+            output_on_failure_only: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(report.failed_tasks(), vec![0]);
+}
+
+#[tokio::test]
+async fn binary_safe_logs_are_byte_for_byte() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..=255).cycle().take(64 * 1024).collect();
+    let input = log_dir.path().join("input.bin");
+    std::fs::write(&input, &data).unwrap();
+
+    let cmd = format!("cat {input:?}");
+    for output_on_failure_only in [false, true] {
+        let report = run_in(
+            log_dir.path(),
+            &[&cmd, &format!("{cmd}; false")],
+            RunOptions {
+                binary_safe: true,
+                strip_ansi_logs: true,
+                output_on_failure_only,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert_eq!(report.failed_tasks(), vec![1]);
+        assert_eq!(
+            std::fs::read(log_dir.path().join("1.stdout")).unwrap(),
+            data
+        );
+        if !output_on_failure_only {
+            assert_eq!(
+                std::fs::read(log_dir.path().join("0.stdout")).unwrap(),
+                data
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn shell_annotation_overrides_the_shell() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_commands(RunOptions {
+        tasks: vec![
+            Task::from("#!sh echo $0").with_shell_annotation(),
+            Task::from("echo $0"),
+        ],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert!(report.success());
+    let read = |name| std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+    assert_eq!(read("0.stdout"), "sh\n");
+    assert_eq!(read("1.stdout"), format!("{}\n", ptsd::DEFAULT_SHELL));
+}
+
+#[tokio::test]
+async fn dedup_skips_repeated_tasks_and_keeps_indices() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo a", "echo a", "echo b"],
+        RunOptions {
+            dedup: true,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(report.success());
+    assert_eq!(report.tasks[1].outcome, TaskOutcome::Duplicate { of: 0 });
+    assert!(!log_dir.path().join("1.stdout").exists());
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("2.stdout")).unwrap(),
+        "b\n"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn full_disk_aborts_the_run() {
+    let log_dir = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink("/dev/full", log_dir.path().join("1.stdout")).unwrap();
+
+    let result = run_commands(RunOptions {
+        tasks: vec!["sleep 10".into(), "yes".into()],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        jobs: NonZeroUsize::new(2).unwrap(),
+        disable_progress: true,
+        abort_on_disk_full: true,
+        ..Default::default()
+    })
+    .await;
+
+    let report = result.unwrap();
+    assert!(report.disk_full);
+    assert!(!report.success());
+    // The tasks that had already started are still reported.
+    let indices: Vec<_> = report.tasks.iter().map(|task| task.index).collect();
+    assert_eq!(indices, [0, 1]);
+    assert_eq!(report.tasks[0].outcome, TaskOutcome::Interrupted);
+}
+
+/// The output captured by a [`MemorySink`]: task name, stream and contents.
+type CapturedOutputs = Arc<Mutex<Vec<(String, Stream, Vec<u8>)>>>;
+
+#[derive(Debug, Default)]
+struct MemorySink {
+    outputs: CapturedOutputs,
+}
+
+struct MemoryLog {
+    key: (String, Stream),
+    buffer: Vec<u8>,
+    outputs: CapturedOutputs,
+}
+
+impl Write for MemoryLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogSink for MemoryLog {
+    fn finish(self: Box<Self>, _success: bool) -> io::Result<()> {
+        let (task_name, stream) = self.key;
+        self.outputs
+            .lock()
+            .unwrap()
+            .push((task_name, stream, self.buffer));
+        Ok(())
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn open(&self, task_name: &str, _task: &Task, stream: Stream) -> io::Result<Box<dyn LogSink>> {
+        Ok(Box::new(MemoryLog {
+            key: (task_name.to_string(), stream),
+            buffer: Vec::new(),
+            outputs: self.outputs.clone(),
+        }))
+    }
+}
+
+#[tokio::test]
+async fn output_goes_to_a_custom_sink() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let sink = Arc::new(MemorySink::default());
+    let report = run_in(
+        log_dir.path(),
+        &["echo out; echo err >&2"],
+        RunOptions {
+            output_sink: Some(sink.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(report.success());
+    let mut outputs = sink.outputs.lock().unwrap().clone();
+    outputs.sort_by_key(|(_, stream, _)| *stream == Stream::Stderr);
+    assert_eq!(
+        outputs,
+        [
+            ("0".to_string(), Stream::Stdout, b"out\n".to_vec()),
+            ("0".to_string(), Stream::Stderr, b"err\n".to_vec()),
+        ]
+    );
+    assert!(!log_dir.path().join("0.stdout").exists());
+}
+
+#[tokio::test]
+async fn env_passthrough_clears_everything_else() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo \"$PATH\"; echo \"${CARGO_MANIFEST_DIR-unset}\""],
+        RunOptions {
+            env_passthrough: Some(vec!["PATH".to_string()]),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(report.success());
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        format!("{}\nunset\n", std::env::var("PATH").unwrap())
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn latest_symlink_follows_the_last_run() {
+    let root = tempfile::tempdir().unwrap();
+    let link = root.path().join("latest");
+    for run in ["run1", "run2"] {
+        let log_dir = root.path().join(run);
+        std::fs::create_dir(&log_dir).unwrap();
+        run_in(
+            &log_dir,
+            &["true"],
+            RunOptions {
+                latest_symlink: Some(link.clone()),
+                ..RunOptions::default()
+            },
+        )
+        .await;
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            log_dir.canonicalize().unwrap()
+        );
+    }
+    assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 3);
+}
+
+#[tokio::test]
+async fn events_report_the_run_as_it_goes() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let (mut events, run) = run_commands_with_events(RunOptions {
+        tasks: vec!["printf 'one\\ntwo'".into()],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        ..RunOptions::default()
+    });
+    let report = run.await.unwrap();
+    assert!(report.success());
+
+    let mut received = Vec::new();
+    while let Some(event) = events.recv().await {
+        received.push(event);
+    }
+    assert!(matches!(
+        &received[0],
+        TaskEvent::Started { index: 0, command } if command == "printf 'one\\ntwo'"
+    ));
+    let lines: Vec<_> = received
+        .iter()
+        .filter_map(|event| match event {
+            TaskEvent::OutputLine {
+                index: 0,
+                stream: Stream::Stdout,
+                line,
+            } => Some(line.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(lines, ["one", "two"]);
+    assert!(matches!(
+        &received[received.len() - 2],
+        TaskEvent::Finished(report) if report.outcome == TaskOutcome::Succeeded
+    ));
+    assert!(matches!(
+        received.last().unwrap(),
+        TaskEvent::RunComplete {
+            success: true,
+            interrupted: false
+        }
+    ));
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        "one\ntwo"
+    );
+}
+
+#[tokio::test]
+async fn missing_shell_aborts_before_running_anything() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let result = run_commands(RunOptions {
+        tasks: vec!["true".into()],
+        shell: "/nonexistent/shell".to_string(),
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        require_shell_exists: true,
+        ..RunOptions::default()
+    })
+    .await;
+    assert!(matches!(result, Err(SetupError::MissingShell { .. })));
+    assert!(!log_dir.path().join("0.stdout").exists());
+}
+
+#[tokio::test]
+async fn output_filter_rewrites_the_logs_and_can_fail_the_task() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo out; echo err >&2", "echo quiet"],
+        RunOptions {
+            output_filter: Some("tr a-z A-Z".to_string()),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    let read = |name| std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+    assert_eq!(read("0.stdout"), "OUT\n");
+    assert_eq!(read("0.stderr"), "ERR\n");
+
+    let report = run_in(
+        log_dir.path(),
+        &["echo out"],
+        RunOptions {
+            output_filter: Some("cat; exit 4".to_string()),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        report.tasks[0].outcome,
+        TaskOutcome::FilterFailed { exit_code: Some(4) }
+    );
+    assert_eq!(read("0.stdout"), "out\n");
+}
+
+#[tokio::test]
+async fn unmet_preconditions_skip_tasks() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_commands(RunOptions {
+        tasks: vec![
+            Task {
+                only_if: Some("true".to_string()),
+                ..Task::from("echo ran")
+            },
+            Task {
+                only_if: Some("false".to_string()),
+                ..Task::from("echo ran")
+            },
+        ],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        ..RunOptions::default()
+    })
+    .await
+    .unwrap();
+
+    assert!(report.success());
+    assert_eq!(report.tasks[0].outcome, TaskOutcome::Succeeded);
+    assert_eq!(report.tasks[1].outcome, TaskOutcome::PreconditionUnmet);
+    assert!(!log_dir.path().join("1.stdout").exists());
+}
+
+#[tokio::test]
+async fn checksum_manifest_lists_every_log() {
+    let log_dir = tempfile::tempdir().unwrap();
+    run_in(
+        log_dir.path(),
+        &["echo a"],
+        RunOptions {
+            checksum_logs: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join(ptsd::MANIFEST_FILE_NAME)).unwrap(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  0.stderr\n\
+         87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7  0.stdout\n"
+    );
+}
+
+#[tokio::test]
+async fn dedupe_output_collapses_repeated_lines() {
+    let log_dir = tempfile::tempdir().unwrap();
+    run_in(
+        log_dir.path(),
+        &["yes tick | head -n 5; echo done; echo done; printf tail"],
+        RunOptions {
+            dedupe_output: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        "tick (repeated 5 times)\ndone (repeated 2 times)\ntail"
+    );
+}
+
+#[tokio::test]
+async fn dedupe_output_splits_lines_without_an_end() {
+    let log_dir = tempfile::tempdir().unwrap();
+    run_in(
+        log_dir.path(),
+        &["head -c 200000 /dev/zero | tr '\\0' a"],
+        RunOptions {
+            dedupe_output: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    // Cut into 64 KiB lines, the first three of which are the same.
+    let piece = "a".repeat(64 * 1024);
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        format!(
+            "{piece} (repeated 3 times)\n{}",
+            "a".repeat(200000 - 3 * piece.len())
+        )
+    );
+}
+
+#[tokio::test]
+async fn max_line_length_cuts_long_lines_short() {
+    let log_dir = tempfile::tempdir().unwrap();
+    run_in(
+        log_dir.path(),
+        &["echo abcdefgh; echo abcdefgh; echo xy; printf 'long tail'"],
+        RunOptions {
+            dedupe_output: true,
+            max_line_length: NonZeroUsize::new(4),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        "abcd (truncated 4 bytes) (repeated 2 times)\nxy\nlong (truncated 5 bytes)"
+    );
+}
+
+#[tokio::test]
+async fn max_line_length_keeps_huge_lines_whole_in_json_logs() {
+    let log_dir = tempfile::tempdir().unwrap();
+    run_in(
+        log_dir.path(),
+        &["head -c 200000 /dev/zero | tr '\\0' a; echo; echo next"],
+        RunOptions {
+            log_json: true,
+            max_line_length: NonZeroUsize::new(1024),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    let log = std::fs::read_to_string(log_dir.path().join("0.jsonl")).unwrap();
+    let lines: Vec<_> = log
+        .lines()
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            record["line"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            format!("{} (truncated {} bytes)", "a".repeat(1024), 200000 - 1024),
+            "next".to_string()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn spawn_rate_spaces_out_task_starts() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["true", "true", "true", "true"],
+        RunOptions {
+            jobs: NonZeroUsize::new(4).unwrap(),
+            spawn_rate: Some(4.0),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    // The first four start at once, as a burst.
+    assert!(report.duration < std::time::Duration::from_millis(500));
+
+    let report = run_in(
+        log_dir.path(),
+        &["true", "true", "true", "true"],
+        RunOptions {
+            jobs: NonZeroUsize::new(4).unwrap(),
+            spawn_rate: Some(2.0),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    // Two start at once, the other two half a second apart.
+    assert!(report.duration >= std::time::Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn zero_ramp_starts_all_jobs_at_once() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["sleep 0.5", "sleep 0.5", "sleep 0.5", "sleep 0.5"],
+        RunOptions {
+            jobs: NonZeroUsize::new(4).unwrap(),
+            ramp: Some(std::time::Duration::ZERO),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    assert!(report.duration < std::time::Duration::from_millis(1500));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn tasks_wait_for_memory_to_be_available() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["sleep 0.2", "sleep 0.2", "sleep 0.2"],
+        RunOptions {
+            jobs: NonZeroUsize::new(3).unwrap(),
+            // More than any machine has, so tasks only start once none run.
+            reserve_memory_per_task: Some(1 << 60),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    // One after the other, rather than all at once.
+    assert!(report.duration >= std::time::Duration::from_millis(600));
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn messages_and_summary_go_to_the_given_writers() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let messages = SharedBuffer::default();
+    let report = run_in(
+        log_dir.path(),
+        &["true", "false"],
+        RunOptions {
+            messages: Some(MessageWriter::new(messages.clone())),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        String::from_utf8(messages.0.lock().unwrap().clone()).unwrap(),
+        format!("Writing standard outputs to {:?}\n", log_dir.path())
+    );
+
+    let mut summary = Vec::new();
+    report.write_summary(&mut summary, None).unwrap();
+    assert_eq!(
+        String::from_utf8(summary).unwrap(),
+        format!(
+            "The following tasks failed: [1]\nYou can view their output in {:?}\n",
+            log_dir.path()
+        )
+    );
+}
+
+/// Send `request` to `addr`, returning the status line and the body of the
+/// response.
+async fn http(addr: &str, request: &str) -> (String, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[tokio::test]
+async fn serve_answers_with_the_state_of_the_run() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let done = log_dir.path().join("done");
+    let waiting = format!("while [ ! -e {done:?} ]; do sleep 0.05; done");
+    let messages = SharedBuffer::default();
+    let commands = [waiting.as_str()];
+    let run = run_in(
+        log_dir.path(),
+        &commands,
+        RunOptions {
+            serve: Some("127.0.0.1:0".parse().unwrap()),
+            messages: Some(MessageWriter::new(messages.clone())),
+            ..RunOptions::default()
+        },
+    );
+    let requests = async {
+        let addr = loop {
+            let messages = String::from_utf8(messages.0.lock().unwrap().clone()).unwrap();
+            if let Some((_, rest)) = messages.split_once("Serving the state of the run on http://")
+            {
+                break rest.split_once('/').unwrap().0.to_string();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        let (status, body) = http(&addr, "GET / HTTP/1.1\r\nHost: ptsd\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let snapshot: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(snapshot["total"], 1);
+        assert_eq!(snapshot["tasks"][0]["command"], waiting.as_str());
+        let (status, _) = http(&addr, "GET /tasks HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = http(&addr, "POST / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        std::fs::write(&done, "").unwrap();
+    };
+    let (report, ()) = tokio::join!(run, requests);
+    assert!(report.success());
+}
+
+#[tokio::test]
+async fn output_beyond_the_shared_budget_is_spilled_to_disk() {
+    let log_dir = tempfile::tempdir().unwrap();
+    // The task only succeeds if its output was already spilled to its log
+    // while it runs.
+    let task = Task {
+        cwd: Some(log_dir.path().to_path_buf()),
+        ..Task::from("printf '%0100d' 0; sleep 0.2; test -e 0.stdout")
+    };
+    let run = |max_concurrent_output_bytes| {
+        run_commands(RunOptions {
+            tasks: vec![task.clone()],
+            log_dir: Some(log_dir.path().to_path_buf()),
+            disable_progress: true,
+            output_on_failure_only: true,
+            max_concurrent_output_bytes,
+            ..RunOptions::default()
+        })
+    };
+
+    assert!(!run(None).await.unwrap().success());
+    std::fs::remove_file(log_dir.path().join("0.stdout")).unwrap();
+    let report = run(Some(10)).await.unwrap();
+    assert!(report.success());
+    assert!(!log_dir.path().join("0.stdout").exists());
+}
+
+#[tokio::test]
+async fn summary_table_lists_every_task_in_order() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["true", "sleep 0.2; exit 3"],
+        RunOptions {
+            jobs: NonZeroUsize::new(2).unwrap(),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    let table = |order| {
+        let mut table = Vec::new();
+        report.write_table(&mut table, order).unwrap();
+        String::from_utf8(table).unwrap()
+    };
+    let by_index = table(SummaryOrder::Index);
+    let lines: Vec<_> = by_index.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("#  COMMAND            STATUS  EXIT  DURATION"));
+    assert!(lines[1].starts_with("0  true               ok         0"));
+    assert!(lines[2].starts_with("1  sleep 0.2; exit 3  failed     3"));
+    assert!(table(SummaryOrder::Duration)
+        .lines()
+        .nth(1)
+        .unwrap()
+        .starts_with("1 "));
+}
+
+#[tokio::test]
+async fn no_log_runs_without_a_log_dir() {
+    let parent = tempfile::tempdir().unwrap();
+    let log_dir = parent.path().join("logs");
+    let report = run_in(
+        &log_dir,
+        &["echo hello", "exit 3", "true"],
+        RunOptions {
+            no_log: true,
+            fail_on_empty_output: Some(EmptyOutputPolicy::Stdout),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    assert_eq!(report.log_dir, None);
+    assert!(!log_dir.exists());
+    let outcomes: Vec<_> = report.tasks.iter().map(|task| &task.outcome).collect();
+    assert_eq!(
+        outcomes,
+        [
+            &TaskOutcome::Succeeded,
+            &TaskOutcome::Failed { exit_code: Some(3) },
+            &TaskOutcome::NoOutput,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn tasks_know_their_place_in_the_run() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let script = "echo $PTSD_TASK_INDEX $PTSD_TASK_NAME $PTSD_TASK_TOTAL";
+    let mut named = Task::from(script);
+    named.name = Some("shard".to_string());
+    run_commands(RunOptions {
+        tasks: vec![script.into(), named],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        env_passthrough: Some(Vec::new()),
+        ..RunOptions::default()
+    })
+    .await
+    .unwrap();
+
+    let read_log = |name: &str| std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+    assert_eq!(read_log("0.stdout"), "0 0 2\n");
+    assert_eq!(read_log("1-shard.stdout"), "1 1-shard 2\n");
+}
+
+#[tokio::test]
+async fn jobs_are_limited_to_the_number_of_tasks() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let messages = SharedBuffer::default();
+    run_in(
+        log_dir.path(),
+        &["sleep 0.3", "sleep 0.3"],
+        RunOptions {
+            jobs: NonZeroUsize::new(64).unwrap(),
+            stats_interval: Some(std::time::Duration::from_millis(100)),
+            messages: Some(MessageWriter::new(messages.clone())),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    let messages = String::from_utf8(messages.0.lock().unwrap().clone()).unwrap();
+    assert!(messages.contains("permits in use: 2/2"), "{messages}");
+}
+
+#[tokio::test]
+async fn tasks_get_their_own_env_and_timeout() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_commands(RunOptions {
+        tasks: vec![
+            Task {
+                env: [("GREETING".to_string(), "hi".to_string())].into(),
+                ..Task::from("echo $GREETING")
+            },
+            Task {
+                timeout: Some(std::time::Duration::from_millis(100)),
+                ..Task::from("sleep 5")
+            },
+        ],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        ..RunOptions::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(report.tasks[0].outcome, TaskOutcome::Succeeded);
+    assert_eq!(report.tasks[1].outcome, TaskOutcome::TimedOut);
+    assert!(report.tasks[1].duration < std::time::Duration::from_secs(2));
+    let stdout = std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap();
+    assert_eq!(stdout, "hi\n");
+}
+
+/// Whether the process `pid` is still alive, as opposed to gone or a zombie.
+fn is_running(pid: &str) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|stat| {
+        !stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .trim_start()
+            .starts_with('Z')
+    })
+}
+
+#[tokio::test]
+async fn timeouts_kill_what_the_task_started_too() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let pid_file = log_dir.path().join("pid");
+    let report = run_commands(RunOptions {
+        tasks: vec![Task {
+            timeout: Some(std::time::Duration::from_millis(300)),
+            ..Task::from(format!("sleep 5 & echo $! > {pid_file:?}; wait"))
+        }],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        // An orphaned `sleep` would hold the pipes open.
+        strip_ansi_logs: true,
+        graceful_interrupts: true,
+        ..RunOptions::default()
+    })
+    .await
+    .unwrap();
+    assert_eq!(report.tasks[0].outcome, TaskOutcome::TimedOut);
+    assert!(report.duration < std::time::Duration::from_secs(3));
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    assert!(!is_running(&pid), "{pid} is still running");
+}
+
+#[tokio::test]
+async fn failed_tasks_are_echoed_to_the_messages() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let messages = SharedBuffer::default();
+    run_in(
+        log_dir.path(),
+        &["echo fine", "echo out; echo err >&2; exit 2"],
+        RunOptions {
+            echo_failures: true,
+            messages: Some(MessageWriter::new(messages.clone())),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    let messages = String::from_utf8(messages.0.lock().unwrap().clone()).unwrap();
+    assert!(!messages.contains("fine"), "{messages}");
+    assert!(messages.ends_with(
+        "==== Task 1 failed: echo out; echo err >&2; exit 2\n\
+         ---- stdout ----\nout\n\
+         ---- stderr ----\nerr\n\
+         ==== End of task 1\n"
+    ));
+}
+
+#[tokio::test]
+async fn dropping_the_run_kills_its_tasks() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let marker = log_dir.path().join("marker");
+    let command = format!("sleep 0.3; touch {marker:?}");
+    let commands = [command.as_str()];
+    let run = run_in(log_dir.path(), &commands, RunOptions::default());
+    let timed_out = tokio::time::timeout(std::time::Duration::from_millis(100), run).await;
+    assert!(timed_out.is_err());
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert!(!marker.exists());
+}
+
+#[tokio::test]
+async fn json_logs_wrap_every_line() {
+    let log_dir = tempfile::tempdir().unwrap();
+    run_in(
+        log_dir.path(),
+        &["echo out; sleep 0.1; echo err >&2; sleep 0.1; printf partial"],
+        RunOptions {
+            log_json: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    assert!(!log_dir.path().join("0.stdout").exists());
+    let log = std::fs::read_to_string(log_dir.path().join("0.jsonl")).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let fields: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            assert_eq!(line["index"], 0);
+            assert!(line["timestamp"].is_string());
+            (
+                line["stream"].as_str().unwrap(),
+                line["line"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        fields,
+        [("stdout", "out"), ("stderr", "err"), ("stdout", "partial")]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn commands_run_at_the_given_niceness() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["nice"],
+        RunOptions {
+            nice: Some(19),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    let stdout = std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap();
+    assert_eq!(stdout, "19\n");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn tasks_are_pinned_to_cpus_round_robin() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let command = "grep Cpus_allowed_list /proc/self/status | cut -f2";
+    let report = run_in(
+        log_dir.path(),
+        &[command, command],
+        RunOptions {
+            cpu_affinity: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    let cpus: Vec<usize> = (0..2)
+        .map(|i| {
+            let stdout = std::fs::read_to_string(log_dir.path().join(format!("{i}.stdout")));
+            stdout.unwrap().trim().parse().unwrap()
+        })
+        .collect();
+    if std::thread::available_parallelism().unwrap().get() > 1 {
+        assert_ne!(cpus[0], cpus[1]);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn resource_limits_apply_to_each_task() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["ulimit -n", "while :; do :; done"],
+        RunOptions {
+            resource_limits: ptsd::ResourceLimits {
+                cpu_seconds: Some(1),
+                open_files: Some(64),
+                ..Default::default()
+            },
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(report.failed_tasks(), [1]);
+    let stdout = std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap();
+    assert_eq!(stdout, "64\n");
+    assert_eq!(report.tasks[1].signal, Some(libc::SIGXCPU));
+    let mut histogram = Vec::new();
+    report.write_failure_histogram(&mut histogram).unwrap();
+    assert_eq!(
+        String::from_utf8(histogram).unwrap(),
+        "Failures by cause:\n  exceeded the CPU time limit: 1 task\n"
+    );
+}
+
+#[tokio::test]
+async fn selected_tasks_keep_their_indices() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo 0", "echo 1", "exit 2", "echo 3"],
+        RunOptions {
+            select: Some(vec![1..=2]),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    let indices: Vec<_> = report.tasks.iter().map(|task| task.index).collect();
+    assert_eq!(indices, [1, 2]);
+    assert_eq!(report.failed_tasks(), [2]);
+    let read_log = |name: &str| std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+    assert_eq!(read_log("1.stdout"), "1\n");
+    assert!(!log_dir.path().join("0.stdout").exists());
+    assert!(!log_dir.path().join("3.stdout").exists());
+}
+
+#[tokio::test]
+async fn failure_histogram_counts_each_cause() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["exit 1", "exit 127", "true", "exit 127", "kill -9 $$"],
+        RunOptions::default(),
+    )
+    .await;
+    let mut histogram = Vec::new();
+    report.write_failure_histogram(&mut histogram).unwrap();
+    assert_eq!(
+        String::from_utf8(histogram).unwrap(),
+        "Failures by cause:\n  exit code 127: 2 tasks\n  exit code 1: 1 task\n  killed by a signal: 1 task\n"
+    );
+}
+
+#[tokio::test]
+async fn junit_report_has_a_test_case_per_task() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let junit = log_dir.path().join("junit.xml");
+    let report = run_in(
+        log_dir.path(),
+        &["true", "echo '<boom> & co' >&2; exit 2"],
+        RunOptions {
+            junit_report: Some(junit.clone()),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert_eq!(report.failed_tasks(), [1]);
+    let junit = std::fs::read_to_string(junit).unwrap();
+    assert!(junit.contains(r#"tests="2" failures="1" errors="0" skipped="0""#));
+    assert!(junit.contains(r#"<testcase name="0: true" classname="ptsd""#));
+    assert!(junit.contains(
+        r#"<testcase name="1: echo '&lt;boom&gt; &amp; co' &gt;&amp;2; exit 2" classname="ptsd""#
+    ));
+    assert!(junit.contains("<failure message=\"exit code 2\">&lt;boom&gt; &amp; co\n</failure>"));
+}
+
+#[tokio::test]
+async fn supervised_tasks_are_restarted_and_keep_their_logs() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo up; exit 1", "true"],
+        RunOptions {
+            supervise: true,
+            max_restarts: Some(2),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    assert_eq!(report.tasks[0].restarts, 2);
+    assert_eq!(
+        report.tasks[0].outcome,
+        TaskOutcome::Failed { exit_code: Some(1) }
+    );
+    assert_eq!(report.tasks[1].restarts, 2);
+    let stdout = std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap();
+    assert_eq!(stdout, "up\nup\nup\n");
+
+    let mut summary = Vec::new();
+    report.write_summary(&mut summary, None).unwrap();
+    assert!(String::from_utf8(summary)
+        .unwrap()
+        .ends_with("Task 0 was restarted 2 times\nTask 1 was restarted 2 times\n"));
+}
+
+#[tokio::test]
+async fn oom_killed_tasks_are_relaunched_once_with_fewer_alongside() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let dir = log_dir.path().display();
+    let killed_once = format!(
+        "if [ -e {dir}/relaunched ]; then echo relaunched >> {dir}/order; \
+         else touch {dir}/relaunched; kill -9 $$; fi"
+    );
+    let slow = format!("sleep 0.5; echo slow >> {dir}/order");
+    let report = run_in(
+        log_dir.path(),
+        &[&killed_once, &slow, "bash -c 'kill -9 $$'; exit $?"],
+        RunOptions {
+            jobs: NonZeroUsize::new(2).unwrap(),
+            relaunch_on_oom: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    assert_eq!(report.tasks[0].outcome, TaskOutcome::Succeeded);
+    assert_eq!(report.tasks[0].restarts, 1);
+    assert_eq!(report.tasks[1].restarts, 0);
+    // Exiting with 137 looks the same, but it's only relaunched once.
+    assert_eq!(
+        report.tasks[2].outcome,
+        TaskOutcome::Failed {
+            exit_code: Some(137)
+        }
+    );
+    assert_eq!(report.tasks[2].restarts, 1);
+    // The relaunch waited for the slow task to free up its slot.
+    let order = std::fs::read_to_string(log_dir.path().join("order")).unwrap();
+    assert_eq!(order, "slow\nrelaunched\n");
+}
+
+#[tokio::test]
+async fn combined_log_interleaves_every_task() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let combined_log = log_dir.path().join("combined.log");
+    run_in(
+        log_dir.path(),
+        &["echo one; sleep 0.2; echo three", "sleep 0.1; echo two >&2"],
+        RunOptions {
+            jobs: NonZeroUsize::new(2).unwrap(),
+            combined_log: Some(combined_log.clone()),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+
+    let combined = std::fs::read_to_string(combined_log).unwrap();
+    let lines: Vec<_> = combined
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect();
+    assert_eq!(
+        lines,
+        ["#0 stdout: one", "#1 stderr: two", "#0 stdout: three"]
+    );
+    let stdout = std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap();
+    assert_eq!(stdout, "one\nthree\n");
+}
+
+#[tokio::test]
+async fn failed_precheck_runs_no_tasks() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let marker = log_dir.path().join("ran");
+    let result = run_commands(RunOptions {
+        tasks: vec![format!("touch {marker:?}").into()],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        precheck: Some("echo not ready; exit 4".to_string()),
+        ..RunOptions::default()
+    })
+    .await;
+
+    let Err(SetupError::ProbeFailed {
+        exit_code, output, ..
+    }) = result
+    else {
+        panic!("{result:?}");
+    };
+    assert_eq!(exit_code, Some(4));
+    assert_eq!(output, "not ready");
+    assert!(!marker.exists());
+}
+
+#[tokio::test]
+async fn prelaunch_check_finds_missing_working_directories() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let marker = log_dir.path().join("ran");
+    let result = run_commands(RunOptions {
+        tasks: vec![
+            format!("touch {marker:?}").into(),
+            Task {
+                cwd: Some(log_dir.path().join("missing")),
+                ..Task::from("true")
+            },
+            "if then".into(),
+        ],
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        prelaunch_check: true,
+        ..RunOptions::default()
+    })
+    .await;
+
+    let Err(SetupError::PrelaunchCheck(problems)) = result else {
+        panic!("{result:?}");
+    };
+    let indices: Vec<_> = problems.iter().map(|(i, _, _)| *i).collect();
+    assert_eq!(indices, [1, 2]);
+    assert!(problems[0].2.contains("missing"), "{problems:?}");
+    assert!(!marker.exists());
+}
+
+#[tokio::test]
+async fn logs_end_with_a_single_newline() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["printf a", "printf 'b\\n\\n\\n'", "printf '\\n\\n'", "true"],
+        RunOptions {
+            ensure_final_newline: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    let read = |i| std::fs::read_to_string(log_dir.path().join(format!("{i}.stdout"))).unwrap();
+    assert_eq!(
+        [read(0), read(1), read(2), read(3)],
+        ["a\n", "b\n", "\n", ""]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn identical_logs_are_hard_linked() {
+    use std::os::unix::fs::MetadataExt;
+
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["echo OK", "echo OK", "echo KO", "echo OK >&2"],
+        RunOptions {
+            dedupe_logs: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+
+    let inode = |name: &str| std::fs::metadata(log_dir.path().join(name)).unwrap().ino();
+    assert_eq!(inode("0.stdout"), inode("1.stdout"));
+    assert_eq!(inode("0.stdout"), inode("3.stderr"));
+    assert_ne!(inode("0.stdout"), inode("2.stdout"));
+    // Empty logs are left alone.
+    assert_ne!(inode("0.stderr"), inode("1.stderr"));
+    let read = |name: &str| std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+    assert_eq!(read("1.stdout"), "OK\n");
+    assert_eq!(read("2.stdout"), "KO\n");
+}
+
+#[tokio::test]
+async fn tasks_that_print_to_stderr_fail() {
+    let log_dir = tempfile::tempdir().unwrap();
+    for no_log in [false, true] {
+        let report = run_in(
+            log_dir.path(),
+            &["echo fine", "echo warning >&2", "echo error >&2; exit 2"],
+            RunOptions {
+                fail_on_stderr: true,
+                no_log,
+                ..RunOptions::default()
+            },
+        )
+        .await;
+        let outcomes: Vec<_> = report.tasks.iter().map(|task| &task.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [
+                &TaskOutcome::Succeeded,
+                &TaskOutcome::WroteToStderr,
+                &TaskOutcome::Failed { exit_code: Some(2) },
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn streamed_tasks_start_as_they_arrive() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let marker = log_dir.path().join("marker");
+    let (sender, receiver) = tokio::sync::mpsc::channel(1);
+    let run = tokio::spawn(run_commands(RunOptions {
+        tasks: vec!["true".into()],
+        streamed_tasks: Some(receiver.into()),
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        dedup: true,
+        ..RunOptions::default()
+    }));
+
+    let touch = Task::from(format!("touch {marker:?}"));
+    sender.send(touch.clone()).await.unwrap();
+    // The stream is still open, so the task can only have been run as it
+    // arrived.
+    for _ in 0..100 {
+        if marker.exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(marker.exists());
+    sender.send(touch).await.unwrap();
+    drop(sender);
+
+    let report = run.await.unwrap().unwrap();
+    let outcomes: Vec<_> = report.tasks.iter().map(|task| &task.outcome).collect();
+    assert_eq!(
+        outcomes,
+        [
+            &TaskOutcome::Succeeded,
+            &TaskOutcome::Succeeded,
+            &TaskOutcome::Duplicate { of: 1 },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn streamed_tasks_of_known_length_count_towards_the_total() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let (sender, receiver) = tokio::sync::mpsc::channel(1);
+    let run = tokio::spawn(run_commands(RunOptions {
+        tasks: vec!["echo $PTSD_TASK_TOTAL".into()],
+        streamed_tasks: Some(TaskStream::with_len(receiver, 10)),
+        log_dir: Some(log_dir.path().to_path_buf()),
+        disable_progress: true,
+        ..RunOptions::default()
+    }));
+    for _ in 0..10 {
+        sender.send("echo $PTSD_TASK_TOTAL".into()).await.unwrap();
+    }
+    drop(sender);
+
+    let report = run.await.unwrap().unwrap();
+    assert!(report.success());
+    // The log names are padded for all 11 tasks, not just the first.
+    for name in ["00.stdout", "10.stdout"] {
+        let total = std::fs::read_to_string(log_dir.path().join(name)).unwrap();
+        assert_eq!(total, "11\n");
+    }
+}
+
+#[tokio::test]
+async fn output_budget_holds_back_tasks_without_starving_them() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &[
+            "sleep 2; echo late",
+            "head -c 1000000 /dev/zero",
+            "head -c 1000000 /dev/zero >&2",
+        ],
+        RunOptions {
+            jobs: NonZeroUsize::new(3).unwrap(),
+            max_concurrent_output_bytes: Some(4096),
+            // Anything that pipes the output through ptsd.
+            strip_ansi_logs: true,
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+
+    // The quiet task doesn't hold on to the budget while it sleeps.
+    assert!(report.tasks[1].duration < std::time::Duration::from_secs(2));
+    let len = |name: &str| std::fs::metadata(log_dir.path().join(name)).unwrap().len();
+    assert_eq!(len("1.stdout"), 1_000_000);
+    assert_eq!(len("2.stderr"), 1_000_000);
+    assert_eq!(len("0.stdout"), 5);
+}
+
+/// Fill `path` with `size` bytes, last modified `age` ago.
+fn old_file(path: &Path, size: usize, age: std::time::Duration) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, vec![b'x'; size]).unwrap();
+    let file = std::fs::File::options().write(true).open(path).unwrap();
+    file.set_modified(std::time::SystemTime::now() - age)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn max_log_dir_size_deletes_the_oldest_logs_first() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let hour = std::time::Duration::from_secs(3600);
+    let oldest = log_dir.path().join("earlier/0.stdout");
+    let older = log_dir.path().join("older.stdout");
+    let old = log_dir.path().join("old.stdout");
+    old_file(&oldest, 100, 3 * hour);
+    old_file(&older, 100, 2 * hour);
+    old_file(&old, 100, hour);
+
+    // The logs of the run take 6 bytes, which leaves room for two old ones.
+    run_in(
+        log_dir.path(),
+        &["echo hi", "echo ho"],
+        RunOptions {
+            max_log_dir_size: Some(250),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(!oldest.exists());
+    assert!(older.exists());
+    assert!(old.exists());
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        "hi\n"
+    );
+}
+
+#[tokio::test]
+async fn max_log_dir_size_keeps_the_logs_of_the_run() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let old = log_dir.path().join("earlier.stdout");
+    old_file(&old, 100, std::time::Duration::from_secs(3600));
+    let messages = SharedBuffer::default();
+    run_in(
+        log_dir.path(),
+        &["echo hi"],
+        RunOptions {
+            max_log_dir_size: Some(1),
+            messages: Some(MessageWriter::new(messages.clone())),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(!old.exists());
+    assert_eq!(
+        std::fs::read_to_string(log_dir.path().join("0.stdout")).unwrap(),
+        "hi\n"
+    );
+    let messages = String::from_utf8(messages.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        messages.contains("The logs of this run alone take 3 bytes, more than the 1 bytes allowed"),
+        "{messages}"
+    );
+}
+
+/// The most tasks of `report` that were running at the same time.
+fn max_concurrency(report: &RunReport) -> usize {
+    let mut changes: Vec<_> = report
+        .tasks
+        .iter()
+        .flat_map(|task| [(task.started_at, 1), (task.finished_at, -1)])
+        .collect();
+    // Ending before starting at the same instant.
+    changes.sort();
+    let mut running = 0;
+    let mut max = 0;
+    for (_, change) in changes {
+        running += change;
+        max = max.max(running);
+    }
+    max as usize
+}
+
+#[tokio::test]
+async fn ramp_starts_jobs_one_at_a_time() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["sleep 1"; 4],
+        RunOptions {
+            jobs: NonZeroUsize::new(4).unwrap(),
+            // A slot every 200ms.
+            ramp: Some(std::time::Duration::from_millis(600)),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    let mut starts: Vec<_> = report.tasks.iter().map(|task| task.started_at).collect();
+    starts.sort();
+    for pair in starts.windows(2) {
+        let gap = pair[1].duration_since(pair[0]).unwrap();
+        assert!(gap >= std::time::Duration::from_millis(150), "{gap:?}");
+    }
+    // Still running alongside each other once the ramp is done.
+    assert_eq!(max_concurrency(&report), 4);
+    assert!(report.duration < std::time::Duration::from_millis(2500));
+}
+
+#[tokio::test]
+async fn jitter_keeps_the_limit_between_jobs_min_and_jobs() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["sleep 0.5"; 12],
+        RunOptions {
+            jobs: NonZeroUsize::new(4).unwrap(),
+            jobs_min: NonZeroUsize::new(2),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    let concurrency = max_concurrency(&report);
+    assert!((2..=4).contains(&concurrency), "{concurrency}");
+    // At least two at a time, which takes at most 6 rounds of tasks.
+    assert!(report.duration < std::time::Duration::from_millis(4500));
+}
+
+#[tokio::test]
+async fn jobs_min_above_jobs_is_capped_at_jobs() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let report = run_in(
+        log_dir.path(),
+        &["sleep 0.6"; 6],
+        RunOptions {
+            jobs: NonZeroUsize::new(2).unwrap(),
+            jobs_min: NonZeroUsize::new(8),
+            ..RunOptions::default()
+        },
+    )
+    .await;
+    assert!(report.success());
+    assert_eq!(max_concurrency(&report), 2);
+}
@@ -0,0 +1,32 @@
+use ptsd::CommandTransform;
+
+fn transform(substitution: &str, command: &str) -> String {
+    substitution
+        .parse::<CommandTransform>()
+        .unwrap()
+        .apply(command)
+}
+
+#[test]
+fn replaces_the_first_match_unless_global() {
+    assert_eq!(transform("s/a/b/", "a a"), "b a");
+    assert_eq!(transform("s/a/b/g", "a a"), "b b");
+    assert_eq!(transform("s/MAKE/make/i", "Make all"), "make all");
+}
+
+#[test]
+fn supports_sed_replacements_and_delimiters() {
+    assert_eq!(
+        transform(r"s|/opt/(\w+)|/usr/\1 [&]|", "ls /opt/bin/x"),
+        "ls /usr/bin [/opt/bin]/x"
+    );
+    assert_eq!(transform(r"s/\//\\/g", "a/b"), r"a\b");
+    assert_eq!(transform(r"s/x/$HOME \&/", "echo x"), "echo $HOME &");
+}
+
+#[test]
+fn rejects_malformed_substitutions() {
+    for bad in ["", "a/b/c/", "s/a/b", "s/(/x/", "s/a/b/q", "sabc"] {
+        assert!(bad.parse::<CommandTransform>().is_err(), "{bad:?}");
+    }
+}